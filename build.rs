@@ -1,6 +1,13 @@
-extern crate cc;
-
+// The noncegen implementations are Rust, the C ones are only built with
+// the c-simd feature to compare against.
 fn main() {
+    println!("cargo:rerun-if-changed=src/c");
+    #[cfg(feature = "c-simd")]
+    build_c();
+}
+
+#[cfg(feature = "c-simd")]
+fn build_c() {
     let mut base = cc::Build::new();
 
     // Only add MSVC optimization flags when the target is actually MSVC
@@ -1,12 +1,77 @@
 // use aligned_alloc::{aligned_alloc, aligned_free};
 use std::alloc::{alloc, dealloc, Layout};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
+// huge page sizes, transparent huge pages are always 2 MiB
+#[cfg(target_os = "linux")]
+const HUGE_2M: usize = 2 << 20;
+#[cfg(target_os = "linux")]
+const HUGE_1G: usize = 1 << 30;
+
+/// Page size backing a buffer. Huge pages cut the TLB misses of the hashers,
+/// which scatter every nonce over all scoop regions of a buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HugePages {
+    Off,
+    // transparent huge pages, madvise(MADV_HUGEPAGE) on a 2 MiB aligned buffer
+    Transparent,
+    // explicit huge pages from the hugetlb pool, mmap(MAP_HUGETLB)
+    Huge2M,
+    Huge1G,
+}
+
+impl HugePages {
+    // what is tried next if these pages can't be had
+    fn fallback(self) -> HugePages {
+        match self {
+            HugePages::Huge1G => HugePages::Huge2M,
+            HugePages::Huge2M => HugePages::Transparent,
+            _ => HugePages::Off,
+        }
+    }
+}
+
+impl FromStr for HugePages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HugePages, String> {
+        match s {
+            "off" => Ok(HugePages::Off),
+            "thp" => Ok(HugePages::Transparent),
+            "2m" => Ok(HugePages::Huge2M),
+            "1g" => Ok(HugePages::Huge1G),
+            _ => Err(format!("unknown huge page size '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for HugePages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            HugePages::Off => "off",
+            HugePages::Transparent => "thp",
+            HugePages::Huge2M => "2m",
+            HugePages::Huge1G => "1g",
+        })
+    }
+}
+
 pub struct PageAlignedByteBuffer {
     data: Option<Arc<Mutex<Vec<u8>>>>,
     // pointer: *mut (),
     pointer: *mut u8,
     layout: Layout,
+    // length of the mapping of a MAP_HUGETLB buffer, 0 for the heap
+    mapped: usize,
+    // pages the buffer actually got
+    pub pages: HugePages,
+    // worker group hashing into the buffer
+    pub group: usize,
+    // locked in RAM by mlock
+    locked: bool,
 }
 
 impl PageAlignedByteBuffer {
@@ -23,6 +88,84 @@ impl PageAlignedByteBuffer {
         // unsafe {
         //     data = Vec::from_raw_parts(pointer as *mut u8, buffer_size, buffer_size);
         // }
+        Self::from_raw(pointer, buffer_size, layout, 0, HugePages::Off)
+    }
+
+    /// Allocates a buffer backed by `pages`, falling back to smaller pages
+    /// down to normal ones if they can't be had. Check `pages` for the result.
+    pub fn with_huge_pages(buffer_size: usize, pages: HugePages) -> Self {
+        let mut pages = pages;
+        loop {
+            match pages {
+                HugePages::Off => return Self::new(buffer_size),
+                HugePages::Transparent => {
+                    if let Some(x) = Self::transparent(buffer_size) {
+                        return x;
+                    }
+                }
+                HugePages::Huge2M | HugePages::Huge1G => {
+                    if let Some(x) = Self::hugetlb(buffer_size, pages) {
+                        return x;
+                    }
+                }
+            }
+            pages = pages.fallback();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn transparent(buffer_size: usize) -> Option<Self> {
+        // madvise succeeds even if transparent huge pages are switched off
+        let enabled = std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled").ok()?;
+        if enabled.contains("[never]") {
+            return None;
+        }
+        let layout = Layout::from_size_align(buffer_size, HUGE_2M).ok()?;
+        let pointer = unsafe { alloc(layout) };
+        assert!(!pointer.is_null(), "Allocation failed");
+        if unsafe { libc::madvise(pointer as *mut libc::c_void, buffer_size, libc::MADV_HUGEPAGE) } != 0 {
+            unsafe { dealloc(pointer, layout) };
+            return None;
+        }
+        Some(Self::from_raw(pointer, buffer_size, layout, 0, HugePages::Transparent))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn hugetlb(buffer_size: usize, pages: HugePages) -> Option<Self> {
+        let (page, flag) = match pages {
+            HugePages::Huge1G => (HUGE_1G, libc::MAP_HUGE_1GB),
+            _ => (HUGE_2M, libc::MAP_HUGE_2MB),
+        };
+        // mappings cover whole huge pages
+        let mapped = buffer_size.div_ceil(page) * page;
+        let layout = Layout::from_size_align(buffer_size, page).ok()?;
+        let pointer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | flag,
+                -1,
+                0,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Self::from_raw(pointer as *mut u8, buffer_size, layout, mapped, pages))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn transparent(_buffer_size: usize) -> Option<Self> {
+        None
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn hugetlb(_buffer_size: usize, _pages: HugePages) -> Option<Self> {
+        None
+    }
+
+    fn from_raw(pointer: *mut u8, buffer_size: usize, layout: Layout, mapped: usize, pages: HugePages) -> Self {
         let data = unsafe {
             Vec::from_raw_parts(pointer, buffer_size, buffer_size)
         };
@@ -30,20 +173,53 @@ impl PageAlignedByteBuffer {
             data: Some(Arc::new(Mutex::new(data))),
             pointer,
             layout,
+            mapped,
+            pages,
+            group: 0,
+            locked: false,
         }
     }
 
     pub fn get_buffer(&self) -> Arc<Mutex<Vec<u8>>> {
         self.data.as_ref().unwrap().clone()
     }
+
+    /// Locks the buffer in RAM, so it is never swapped out. This faults in
+    /// all its pages, place it on its NUMA node before.
+    #[cfg(unix)]
+    pub fn lock(&mut self) -> io::Result<()> {
+        if unsafe { libc::mlock(self.pointer as *const libc::c_void, self.layout.size()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.locked = true;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn lock(&mut self) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
 }
 
 impl Drop for PageAlignedByteBuffer {
     fn drop(&mut self) {
         std::mem::forget(self.data.take().unwrap());
+        #[cfg(unix)]
+        if self.locked {
+            unsafe {
+                libc::munlock(self.pointer as *const libc::c_void, self.layout.size());
+            }
+        }
         // unsafe {
         //     aligned_free(self.pointer);
         // }
+        #[cfg(target_os = "linux")]
+        if self.mapped > 0 {
+            unsafe {
+                libc::munmap(self.pointer as *mut libc::c_void, self.mapped);
+            }
+            return;
+        }
         unsafe {
             dealloc(self.pointer, self.layout);
         }
@@ -54,7 +230,7 @@ unsafe impl Send for PageAlignedByteBuffer {}
 
 #[cfg(test)]
 mod buffer_tests {
-    use super::PageAlignedByteBuffer;
+    use super::{HugePages, PageAlignedByteBuffer};
 
     #[test]
     fn buffer_creation_destruction_test() {
@@ -63,4 +239,28 @@ mod buffer_tests {
         }
         assert!(true);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn locked_buffer() {
+        // well below the usual 64 KiB to 8 MiB default RLIMIT_MEMLOCK
+        let mut buffer = PageAlignedByteBuffer::new(16 * 1024);
+        buffer.lock().unwrap();
+        buffer.get_buffer().lock().unwrap().fill(0xAA);
+    }
+
+    #[test]
+    fn huge_page_fallback() {
+        // whatever the system offers, the buffer is usable and sized as asked
+        for pages in [HugePages::Off, HugePages::Transparent, HugePages::Huge2M, HugePages::Huge1G] {
+            let buffer = PageAlignedByteBuffer::with_huge_pages(3 * 1024 * 1024 + 4096, pages);
+            let data = buffer.get_buffer();
+            let mut data = data.lock().unwrap();
+            assert_eq!(data.len(), 3 * 1024 * 1024 + 4096);
+            assert_eq!(data.as_ptr() as usize % page_size::get(), 0);
+            data.fill(0xAA);
+            // fallbacks only go to smaller pages
+            assert!(buffer.pages <= pages);
+        }
+    }
 }
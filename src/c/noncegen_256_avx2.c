@@ -42,8 +42,8 @@ void noncegen_avx2(char *cache, const size_t cache_size, const size_t chunk_offs
     memset(&zero[0], 0, 32);
 
     //vars shared
-    uint8_t* buffer = (uint8_t*)malloc(sizeof(uint8_t) * MSHABAL256_VECTOR_SIZE * NONCE_SIZE);
-    uint8_t* final = (uint8_t*)malloc(sizeof(uint8_t) * MSHABAL256_VECTOR_SIZE * HASH_SIZE);
+    uint8_t* buffer = (uint8_t*)_mm_malloc(sizeof(uint8_t) * MSHABAL256_VECTOR_SIZE * NONCE_SIZE, 64);
+    uint8_t* final = (uint8_t*)_mm_malloc(sizeof(uint8_t) * MSHABAL256_VECTOR_SIZE * HASH_SIZE, 64);
 
     // prepare smart SIMD aligned termination strings
     // creation could further be optimized, but not much in it as it only runs once per work package
@@ -249,6 +249,6 @@ void noncegen_avx2(char *cache, const size_t cache_size, const size_t chunk_offs
             n++;
         }
     }
-    free(buffer);
-    free(final);
+    _mm_free(buffer);
+    _mm_free(final);
 }
@@ -38,8 +38,8 @@ void noncegen_avx512f(char *cache, const size_t cache_size, const size_t chunk_o
     char zero[32];  // 256bit of zeros
 
     //vars shared
-    uint8_t* buffer = (uint8_t*)malloc(sizeof(uint8_t) * MSHABAL512_VECTOR_SIZE * NONCE_SIZE);
-    uint8_t* final = (uint8_t*)malloc(sizeof(uint8_t) * MSHABAL512_VECTOR_SIZE * HASH_SIZE);
+    uint8_t* buffer = (uint8_t*)_mm_malloc(sizeof(uint8_t) * MSHABAL512_VECTOR_SIZE * NONCE_SIZE, 64);
+    uint8_t* final = (uint8_t*)_mm_malloc(sizeof(uint8_t) * MSHABAL512_VECTOR_SIZE * HASH_SIZE, 64);
     
     write_seed(seed, numeric_id);
     write_term(term);
@@ -314,6 +314,6 @@ void noncegen_avx512f(char *cache, const size_t cache_size, const size_t chunk_o
             n++;
         }
     }
-    free(buffer);
-    free(final);
+    _mm_free(buffer);
+    _mm_free(final);
 }
@@ -0,0 +1,268 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// v1 reports "no limit" as a page aligned i64::MAX
+const UNLIMITED: u64 = 1 << 62;
+
+/// Memory and cpu limits of the cgroups this process runs in, the effective
+/// limits of a container. `None` where there is no limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CgroupLimits {
+    // memory.max or memory.limit_in_bytes
+    pub memory: Option<u64>,
+    // memory left below the limit, reclaimable page cache counts as free
+    pub memory_avail: Option<u64>,
+    // cpu.max or cfs quota / period, in cpus
+    pub cpu_quota: Option<f64>,
+    // cpuset.cpus.effective or cpuset.effective_cpus
+    pub cpus: Option<Vec<usize>>,
+}
+
+impl CgroupLimits {
+    /// Reads the limits below `root`, which is `/` except for tests. Nested
+    /// cgroups are walked up to their mount point, the tightest limit wins.
+    pub fn read(root: &Path) -> CgroupLimits {
+        let mut limits = CgroupLimits::default();
+        let mounts = mounts(root);
+        let cgroups = fs::read_to_string(root.join("proc/self/cgroup")).unwrap_or_default();
+        for (dir, point) in cgroups.lines().filter_map(|x| cgroup_dir(&mounts, x)) {
+            for dir in dir.ancestors() {
+                limits.read_memory(dir);
+                limits.read_cpu(dir);
+                if limits.cpus.is_none() {
+                    limits.cpus = ["cpuset.cpus.effective", "cpuset.effective_cpus"]
+                        .iter()
+                        .filter_map(|x| fs::read_to_string(dir.join(x)).ok())
+                        .find_map(|x| crate::numa::parse_cpu_list(&x).ok().filter(|x| !x.is_empty()));
+                }
+                if dir == point {
+                    break;
+                }
+            }
+        }
+        limits
+    }
+
+    fn read_memory(&mut self, dir: &Path) {
+        let (limit, usage, inactive) = if dir.join("memory.max").exists() {
+            ("memory.max", "memory.current", "inactive_file")
+        } else {
+            ("memory.limit_in_bytes", "memory.usage_in_bytes", "total_inactive_file")
+        };
+        let limit = match read_u64(&dir.join(limit)) {
+            Some(x) if x < UNLIMITED => x,
+            _ => return,
+        };
+        let usage = read_u64(&dir.join(usage)).unwrap_or(0);
+        let inactive = fs::read_to_string(dir.join("memory.stat"))
+            .ok()
+            .and_then(|x| {
+                x.lines()
+                    .find_map(|x| x.strip_prefix(inactive)?.strip_prefix(' ')?.trim().parse::<u64>().ok())
+            })
+            .unwrap_or(0);
+        let avail = limit.saturating_sub(usage.saturating_sub(inactive));
+        self.memory = Some(self.memory.map_or(limit, |x| x.min(limit)));
+        self.memory_avail = Some(self.memory_avail.map_or(avail, |x| x.min(avail)));
+    }
+
+    fn read_cpu(&mut self, dir: &Path) {
+        // v2: "max 100000" or "<quota> <period>", v1: quota is -1 for none
+        let quota = match fs::read_to_string(dir.join("cpu.max")) {
+            Ok(x) => {
+                let mut parts = x.split_whitespace();
+                match (parts.next().and_then(|x| x.parse::<f64>().ok()), parts.next()) {
+                    (Some(quota), Some(period)) => period.parse::<f64>().ok().map(|x| quota / x),
+                    _ => None,
+                }
+            }
+            Err(_) => {
+                let quota = fs::read_to_string(dir.join("cpu.cfs_quota_us")).ok();
+                let period = read_u64(&dir.join("cpu.cfs_period_us"));
+                match (quota.and_then(|x| x.trim().parse::<i64>().ok()), period) {
+                    (Some(quota), Some(period)) if quota > 0 && period > 0 => Some(quota as f64 / period as f64),
+                    _ => None,
+                }
+            }
+        };
+        if let Some(quota) = quota.filter(|x| *x > 0.0) {
+            self.cpu_quota = Some(self.cpu_quota.map_or(quota, |x| x.min(quota)));
+        }
+    }
+
+    /// The cpus of `affinity` that are in the cpuset.
+    pub fn allowed_cpus(&self, affinity: &[usize]) -> Vec<usize> {
+        match &self.cpus {
+            Some(cpus) => {
+                let allowed: Vec<usize> = affinity.iter().copied().filter(|x| cpus.contains(x)).collect();
+                // an affinity mask outside the cpuset can't be, trust the mask
+                if allowed.is_empty() { affinity.to_vec() } else { allowed }
+            }
+            None => affinity.to_vec(),
+        }
+    }
+
+    /// The cpus this process may run on: its affinity mask within the
+    /// cpuset.
+    pub fn usable_cpus(&self) -> Vec<usize> {
+        let affinity: Vec<usize> = match core_affinity::get_core_ids() {
+            Some(x) if !x.is_empty() => x.iter().map(|x| x.id).collect(),
+            _ => (0..sys_info::cpu_num().unwrap_or(1) as usize).collect(),
+        };
+        self.allowed_cpus(&affinity)
+    }
+
+    /// Number of cpus worth hashing threads for, a quota of 2.5 cpus keeps
+    /// three threads busy.
+    pub fn cpu_count(&self, affinity: &[usize]) -> usize {
+        let cpus = self.allowed_cpus(affinity).len().max(1);
+        match self.cpu_quota {
+            Some(quota) => cpus.min(quota.ceil() as usize).max(1),
+            None => cpus,
+        }
+    }
+}
+
+// a cgroup filesystem mount, v1 hierarchies name their controllers
+struct Mount {
+    root: String,
+    point: PathBuf,
+    controllers: Vec<String>,
+    v2: bool,
+}
+
+fn mounts(root: &Path) -> Vec<Mount> {
+    let mountinfo = fs::read_to_string(root.join("proc/self/mountinfo")).unwrap_or_default();
+    mountinfo
+        .lines()
+        .filter_map(|x| {
+            // <id> <parent> <dev> <root> <mount point> <options> [tags] - <type> <source> <super options>
+            let (mount, fs) = x.split_once(" - ")?;
+            let mount: Vec<&str> = mount.split(' ').collect();
+            let fs: Vec<&str> = fs.split(' ').collect();
+            let v2 = match *fs.first()? {
+                "cgroup2" => true,
+                "cgroup" => false,
+                _ => return None,
+            };
+            Some(Mount {
+                root: mount.get(3)?.to_string(),
+                point: root.join(mount.get(4)?.trim_start_matches('/')),
+                controllers: fs.get(2).map_or(Vec::new(), |x| x.split(',').map(String::from).collect()),
+                v2,
+            })
+        })
+        .collect()
+}
+
+// directory of a /proc/self/cgroup entry, `<id>:<controllers>:<path>`, and
+// the mount point it's below
+fn cgroup_dir<'a>(mounts: &'a [Mount], line: &str) -> Option<(PathBuf, &'a Path)> {
+    let mut parts = line.splitn(3, ':');
+    let (_, controllers, path) = (parts.next()?, parts.next()?, parts.next()?);
+    let mount = if controllers.is_empty() {
+        mounts.iter().find(|x| x.v2)?
+    } else {
+        let controllers: Vec<&str> = controllers.split(',').collect();
+        if !controllers.iter().any(|x| ["memory", "cpu", "cpuset"].contains(x)) {
+            return None;
+        }
+        mounts
+            .iter()
+            .find(|x| !x.v2 && controllers.iter().all(|c| x.controllers.iter().any(|x| x == c)))?
+    };
+    // the path is relative to the root of the mount. A cgroup outside of it,
+    // e.g. `/../..` from a cgroup namespace, is limited by the mount's root.
+    let path = match path.strip_prefix(mount.root.as_str()) {
+        Some(x) if !x.split('/').any(|x| x == "..") => x,
+        _ => "",
+    };
+    Some((mount.point.join(path.trim_start_matches('/')), mount.point.as_path()))
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::ScratchPath;
+
+    fn fake_root(name: &str, mountinfo: &str, cgroup: &str, files: &[(&str, &str)]) -> ScratchPath {
+        let root = ScratchPath::temp(&format!("cgroup-{}", name));
+        fs::create_dir_all(root.join("proc/self")).unwrap();
+        fs::write(root.join("proc/self/mountinfo"), mountinfo).unwrap();
+        fs::write(root.join("proc/self/cgroup"), cgroup).unwrap();
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn cgroup_v2_limits() {
+        // a container with its own cgroup namespace, limited by its parent too
+        let root = fake_root(
+            "v2",
+            "24 30 0:22 / /proc rw - proc proc rw\n\
+             31 24 0:26 / /sys/fs/cgroup ro,nosuid - cgroup2 cgroup2 rw,nsdelegate\n",
+            "0::/app\n",
+            &[
+                ("sys/fs/cgroup/memory.max", "8589934592\n"),
+                ("sys/fs/cgroup/memory.current", "1073741824\n"),
+                ("sys/fs/cgroup/memory.stat", "anon 536870912\nfile 536870912\ninactive_file 268435456\n"),
+                ("sys/fs/cgroup/cpu.max", "250000 100000\n"),
+                ("sys/fs/cgroup/cpuset.cpus.effective", "0-3,8-11\n"),
+                ("sys/fs/cgroup/app/memory.max", "max\n"),
+                ("sys/fs/cgroup/app/cpu.max", "max 100000\n"),
+            ],
+        );
+        let limits = CgroupLimits::read(&root);
+        assert_eq!(limits.memory, Some(8 << 30));
+        assert_eq!(limits.memory_avail, Some((8 << 30) - (3 << 28)));
+        assert_eq!(limits.cpu_quota, Some(2.5));
+        assert_eq!(limits.cpus, Some(vec![0, 1, 2, 3, 8, 9, 10, 11]));
+
+        let affinity: Vec<usize> = (0..16).collect();
+        assert_eq!(limits.allowed_cpus(&affinity), vec![0, 1, 2, 3, 8, 9, 10, 11]);
+        assert_eq!(limits.cpu_count(&affinity), 3);
+    }
+
+    #[test]
+    fn cgroup_v1_limits() {
+        // separate hierarchies, cpu and cpuacct mounted together
+        let root = fake_root(
+            "v1",
+            "33 32 0:29 / /sys/fs/cgroup/cpu,cpuacct rw - cgroup cgroup rw,cpu,cpuacct\n\
+             35 32 0:31 / /sys/fs/cgroup/cpuset rw - cgroup cgroup rw,cpuset\n\
+             36 32 0:32 / /sys/fs/cgroup/memory rw - cgroup cgroup rw,memory\n\
+             42 32 0:38 / /sys/fs/cgroup/unified rw - cgroup2 cgroup2 rw\n",
+            "9:name=systemd:/\n5:cpu,cpuacct:/docker/abc\n4:memory:/docker/abc\n3:cpuset:/docker/abc\n0::/\n",
+            &[
+                ("sys/fs/cgroup/memory/memory.limit_in_bytes", "9223372036854771712\n"),
+                ("sys/fs/cgroup/memory/docker/abc/memory.limit_in_bytes", "4294967296\n"),
+                ("sys/fs/cgroup/memory/docker/abc/memory.usage_in_bytes", "5368709120\n"),
+                ("sys/fs/cgroup/cpu,cpuacct/docker/abc/cpu.cfs_quota_us", "-1\n"),
+                ("sys/fs/cgroup/cpu,cpuacct/docker/abc/cpu.cfs_period_us", "100000\n"),
+                ("sys/fs/cgroup/cpuset/docker/abc/cpuset.effective_cpus", "2\n"),
+            ],
+        );
+        let limits = CgroupLimits::read(&root);
+        assert_eq!(limits.memory, Some(4 << 30));
+        // usage above the limit leaves nothing
+        assert_eq!(limits.memory_avail, Some(0));
+        assert_eq!(limits.cpu_quota, None);
+        assert_eq!(limits.cpu_count(&[0, 1, 2, 3]), 1);
+        assert_eq!(limits.allowed_cpus(&[0, 1]), vec![0, 1]);
+    }
+
+    #[test]
+    fn no_cgroups() {
+        let limits = CgroupLimits::read(Path::new("/nonexistent"));
+        assert_eq!(limits, CgroupLimits::default());
+        assert_eq!(limits.cpu_count(&[0, 1, 2, 3]), 4);
+    }
+}
@@ -1,49 +1,71 @@
-use crate::poc_hashing::noncegen_rust;
-use libc::{c_void, size_t};
+use crate::buffer::{HugePages, PageAlignedByteBuffer};
+use crate::poc_hashing::{noncegen_portable, noncegen_reference, noncegen_rust};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::poc_hashing::{noncegen_avx, noncegen_avx2, noncegen_avx512f, noncegen_sse2};
+use crate::scheduler::WorkerMessage;
+use std::cmp::max;
+use std::fmt;
 use std::slice::from_raw_parts_mut;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, Sender};
+use stopwatch::Stopwatch;
 
 const NUM_SCOOPS: usize = 4096;
 const SCOOP_SIZE: usize = 64;
 const NONCE_SIZE: usize = NUM_SCOOPS * SCOOP_SIZE;
+const BENCH_NONCES_PER_THREAD: usize = 16;
+// 256 MiB, puts every scoop of a nonce on its own 4 KiB page
+const BENCH_CACHE_NONCES: usize = 1024;
+// The startup self-test hashes a few nonces with the scalar reference and
+// compares the backends in use against it. 16 nonces fill the lanes of
+// every cpu backend and one unpacking round of the gpu kernel.
+pub const SELF_TEST_NONCES: usize = 16;
+pub const SELF_TEST_ID: u64 = 7900104405094198526;
+pub const SELF_TEST_START_NONCE: u64 = 1337;
 
-unsafe extern "C" {
-    pub fn init_shabal_sse2() -> ();
-    pub fn init_shabal_avx() -> ();
-    pub fn init_shabal_avx2() -> ();
-    pub fn init_shabal_avx512f() -> ();
-    pub fn noncegen_sse2(
-        cache: *mut c_void,
-        cache_size: size_t,
-        chunk_offset: size_t,
-        numeric_ID: u64,
-        local_startnonce: u64,
-        local_nonces: u64,
-    );
-    pub fn noncegen_avx(
-        cache: *mut c_void,
-        cache_size: size_t,
-        chunk_offset: size_t,
-        numeric_ID: u64,
-        local_startnonce: u64,
-        local_nonces: u64,
-    );
-    pub fn noncegen_avx2(
-        cache: *mut c_void,
-        cache_size: size_t,
-        chunk_offset: size_t,
-        numeric_ID: u64,
-        local_startnonce: u64,
-        local_nonces: u64,
-    );
-    pub fn noncegen_avx512f(
-        cache: *mut c_void,
-        cache_size: size_t,
-        chunk_offset: size_t,
-        numeric_ID: u64,
-        local_startnonce: u64,
-        local_nonces: u64,
-    );
+// the former C implementations, for comparison with the Rust ones
+#[cfg(feature = "c-simd")]
+#[allow(dead_code)]
+pub mod c {
+    use libc::{c_void, size_t};
+
+    unsafe extern "C" {
+        pub fn init_shabal_sse2() -> ();
+        pub fn init_shabal_avx() -> ();
+        pub fn init_shabal_avx2() -> ();
+        pub fn init_shabal_avx512f() -> ();
+        pub fn noncegen_sse2(
+            cache: *mut c_void,
+            cache_size: size_t,
+            chunk_offset: size_t,
+            numeric_ID: u64,
+            local_startnonce: u64,
+            local_nonces: u64,
+        );
+        pub fn noncegen_avx(
+            cache: *mut c_void,
+            cache_size: size_t,
+            chunk_offset: size_t,
+            numeric_ID: u64,
+            local_startnonce: u64,
+            local_nonces: u64,
+        );
+        pub fn noncegen_avx2(
+            cache: *mut c_void,
+            cache_size: size_t,
+            chunk_offset: size_t,
+            numeric_ID: u64,
+            local_startnonce: u64,
+            local_nonces: u64,
+        );
+        pub fn noncegen_avx512f(
+            cache: *mut c_void,
+            cache_size: size_t,
+            chunk_offset: size_t,
+            numeric_ID: u64,
+            local_startnonce: u64,
+            local_nonces: u64,
+        );
+    }
 }
 
 pub struct SafePointer {
@@ -64,37 +86,68 @@ pub struct CpuTask {
 unsafe impl Send for CpuTask {}
 unsafe impl Sync for CpuTask {}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SimdExtension {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     AVX512f,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     AVX2,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     AVX,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     SSE2,
+    Portable,
+    // scalar noncegen_rust
     None,
 }
 
-pub fn init_simd() -> SimdExtension {
+// the name --simd takes
+impl fmt::Display for SimdExtension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::AVX512f => "avx512f",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::AVX2 => "avx2",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::AVX => "avx",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::SSE2 => "sse2",
+            SimdExtension::Portable => "portable",
+            SimdExtension::None => "scalar",
+        })
+    }
+}
+
+// the extensions the cpu supports, widest first
+pub fn available_simd() -> Vec<SimdExtension> {
+    let mut available = Vec::new();
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     {
         if is_x86_feature_detected!("avx512f") {
-            unsafe { init_shabal_avx512f(); }
-            return SimdExtension::AVX512f;
-        } else if is_x86_feature_detected!("avx2") {
-            unsafe { init_shabal_avx2(); }
-            return SimdExtension::AVX2;
-        } else if is_x86_feature_detected!("avx") {
-            unsafe { init_shabal_avx(); }
-            return SimdExtension::AVX;
-        } else if is_x86_feature_detected!("sse2") {
-            unsafe { init_shabal_sse2(); }
-            return SimdExtension::SSE2;
+            available.push(SimdExtension::AVX512f);
+        }
+        if is_x86_feature_detected!("avx2") {
+            available.push(SimdExtension::AVX2);
+        }
+        if is_x86_feature_detected!("avx") {
+            available.push(SimdExtension::AVX);
+        }
+        if is_x86_feature_detected!("sse2") {
+            available.push(SimdExtension::SSE2);
         }
     }
-    SimdExtension::None
+    available.extend([SimdExtension::Portable, SimdExtension::None]);
+    available
+}
+
+pub fn init_simd() -> SimdExtension {
+    available_simd().remove(0)
 }
 
 pub fn hash_cpu(
-    tx: Sender<(u8, u8, u64)>,
+    tx: Sender<WorkerMessage>,
+    worker: usize,
     hasher_task: CpuTask,
     simd_ext: SimdExtension,
 ) -> impl FnOnce() + Send + 'static {
@@ -110,64 +163,134 @@ pub fn hash_cpu(
         // Convert back from usize to raw pointer
         let cache_ptr = cache_ptr_as_usize as *mut u8;
         
-        unsafe {
-            match simd_ext {
-                SimdExtension::AVX512f => noncegen_avx512f(
-                    cache_ptr as *mut c_void,
-                    cache_size,
-                    chunk_offset,
-                    numeric_id,
-                    local_startnonce,
-                    local_nonces,
-                ),
-                SimdExtension::AVX2 => noncegen_avx2(
-                    cache_ptr as *mut c_void,
-                    cache_size,
-                    chunk_offset,
-                    numeric_id,
-                    local_startnonce,
-                    local_nonces,
-                ),
-                SimdExtension::AVX => noncegen_avx(
-                    cache_ptr as *mut c_void,
-                    cache_size,
-                    chunk_offset,
-                    numeric_id,
-                    local_startnonce,
-                    local_nonces,
-                ),
-                SimdExtension::SSE2 => noncegen_sse2(
-                    cache_ptr as *mut c_void,
-                    cache_size,
-                    chunk_offset,
-                    numeric_id,
-                    local_startnonce,
-                    local_nonces,
-                ),
-                _ => {
-                    let data = from_raw_parts_mut(
-                        cache_ptr,
-                        cache_size * NONCE_SIZE,
-                    );
-                    noncegen_rust(
-                        data,
-                        chunk_offset,
-                        numeric_id,
-                        local_startnonce,
-                        local_nonces,
-                    )
-                }
+        let data = unsafe { from_raw_parts_mut(cache_ptr, cache_size * NONCE_SIZE) };
+        // available_simd only hands out extensions the cpu supports
+        match simd_ext {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::AVX512f => unsafe {
+                noncegen_avx512f(data, chunk_offset, numeric_id, local_startnonce, local_nonces)
+            },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::AVX2 => unsafe {
+                noncegen_avx2(data, chunk_offset, numeric_id, local_startnonce, local_nonces)
+            },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::AVX => unsafe {
+                noncegen_avx(data, chunk_offset, numeric_id, local_startnonce, local_nonces)
+            },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            SimdExtension::SSE2 => unsafe {
+                noncegen_sse2(data, chunk_offset, numeric_id, local_startnonce, local_nonces)
+            },
+            SimdExtension::Portable => {
+                noncegen_portable(data, chunk_offset, numeric_id, local_startnonce, local_nonces)
             }
+            _ => noncegen_rust(data, chunk_offset, numeric_id, local_startnonce, local_nonces),
         }
         // report hashing done
-        tx.send((0u8, 1u8, 0))
+        tx.send(WorkerMessage::Ready(worker))
             .expect("CPU task can't communicate with scheduler thread.");
         // report data in hostmem
-        tx.send((0u8, 0u8, local_nonces))
+        tx.send(WorkerMessage::Hashed(worker, local_nonces))
             .expect("CPU task can't communicate with scheduler thread.");
     }
 }
 
+// hashes a few throwaway nonces per thread on `threads` cpu threads and
+// returns the achieved hashing rate in nonces per minute
+pub fn bench_cpu(threads: usize, simd_ext: &SimdExtension) -> f64 {
+    bench_cpu_cache(threads, simd_ext, threads * BENCH_NONCES_PER_THREAD, HugePages::Off).unwrap_or(0.0)
+}
+
+// like bench_cpu, but hashes into a buffer of BENCH_CACHE_NONCES backed by
+// `pages`, scattered wide enough for the page size to matter. None if the
+// pages are unavailable.
+pub fn bench_cpu_pages(threads: usize, simd_ext: &SimdExtension, pages: HugePages) -> Option<f64> {
+    let cache_size = max(threads * BENCH_NONCES_PER_THREAD, BENCH_CACHE_NONCES);
+    bench_cpu_cache(threads, simd_ext, cache_size, pages)
+}
+
+// hashes BENCH_NONCES_PER_THREAD nonces per thread, spread over a buffer of
+// `cache_size` nonces
+fn bench_cpu_cache(threads: usize, simd_ext: &SimdExtension, cache_size: usize, pages: HugePages) -> Option<f64> {
+    if threads == 0 {
+        return Some(0.0);
+    }
+    let buffer = PageAlignedByteBuffer::with_huge_pages(cache_size * NONCE_SIZE, pages);
+    if buffer.pages != pages {
+        return None;
+    }
+    let cache = buffer.get_buffer();
+    let mut cache = cache.lock().unwrap();
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+    let (tx, rx) = channel();
+
+    let sw = Stopwatch::start_new();
+    for i in 0..threads {
+        thread_pool.spawn(hash_cpu(
+            tx.clone(),
+            0,
+            CpuTask {
+                cache: SafePointer {
+                    ptr: cache.as_mut_ptr(),
+                },
+                cache_size,
+                chunk_offset: i * BENCH_NONCES_PER_THREAD,
+                numeric_id: 0,
+                local_startnonce: (i * BENCH_NONCES_PER_THREAD) as u64,
+                local_nonces: BENCH_NONCES_PER_THREAD as u64,
+            },
+            simd_ext.clone(),
+        ));
+    }
+
+    let mut hashed = 0u64;
+    for msg in &rx {
+        if let WorkerMessage::Hashed(_, nonces) = msg {
+            hashed += nonces;
+            if hashed == (threads * BENCH_NONCES_PER_THREAD) as u64 {
+                break;
+            }
+        }
+    }
+    let elapsed = sw.elapsed_ms() as f64;
+    Some(hashed as f64 * 60_000.0 / (elapsed + 1.0))
+}
+
+// the self-test nonces as the scalar reference hashes them, apart from the
+// lane code every backend runs on
+pub fn self_test_reference() -> Vec<u8> {
+    let mut reference = vec![0u8; SELF_TEST_NONCES * NONCE_SIZE];
+    noncegen_reference(&mut reference, 0, SELF_TEST_ID, SELF_TEST_START_NONCE, SELF_TEST_NONCES as u64);
+    reference
+}
+
+// true if `simd_ext` hashes the self-test nonces like the reference, run
+// through hash_cpu like a plotting task
+pub fn self_test_cpu(simd_ext: &SimdExtension, reference: &[u8]) -> bool {
+    let mut cache = vec![0u8; SELF_TEST_NONCES * NONCE_SIZE];
+    let (tx, _rx) = channel();
+    hash_cpu(
+        tx,
+        0,
+        CpuTask {
+            cache: SafePointer {
+                ptr: cache.as_mut_ptr(),
+            },
+            cache_size: SELF_TEST_NONCES,
+            chunk_offset: 0,
+            numeric_id: SELF_TEST_ID,
+            local_startnonce: SELF_TEST_START_NONCE,
+            local_nonces: SELF_TEST_NONCES as u64,
+        },
+        simd_ext.clone(),
+    )();
+    cache == reference
+}
+
 #[cfg(test)]
 mod test {
     extern crate crypto;
@@ -176,6 +299,8 @@ mod test {
     use super::*;
     use crate::plotter;
 
+    type Noncegen = dyn Fn(&mut [u8], usize, u64, u64, u64);
+
     #[test]
     fn test_noncegen() {
         let numeric_id = 7900104405094198526;
@@ -188,72 +313,85 @@ mod test {
             assert_eq!(hasher.result_str(), exp_result_hash);
         };
 
-        if is_x86_feature_detected!("avx512f") {
+        // 19 and 13 nonces leave some for noncegen_rust on every lane count
+        let check = |noncegen: &Noncegen| {
             let mut buf = vec![0; 32 * plotter::NONCE_SIZE as usize];
-            unsafe {
-                init_shabal_avx512f();
-                noncegen_avx512f(
-                    buf.as_mut_ptr() as *mut c_void,
-                    32,
-                    0,
-                    numeric_id,
-                    start_nonce,
-                    32,
-                );
-            }
+            noncegen(&mut buf, 0, numeric_id, start_nonce, 32);
             check_result(&buf);
-        }
-
-        if is_x86_feature_detected!("avx2") {
             let mut buf = vec![0; 32 * plotter::NONCE_SIZE as usize];
-            unsafe {
-                init_shabal_avx2();
-                noncegen_avx2(
-                    buf.as_mut_ptr() as *mut c_void,
-                    32,
-                    0,
-                    numeric_id,
-                    start_nonce,
-                    32,
-                );
-            }
+            noncegen(&mut buf, 0, numeric_id, start_nonce, 19);
+            noncegen(&mut buf, 19, numeric_id, start_nonce + 19, 13);
             check_result(&buf);
-        }
+        };
 
-        if is_x86_feature_detected!("avx") {
-            let mut buf = vec![0; 32 * plotter::NONCE_SIZE as usize];
-            unsafe {
-                init_shabal_avx();
-                noncegen_avx(
-                    buf.as_mut_ptr() as *mut c_void,
-                    32,
-                    0,
-                    numeric_id,
-                    start_nonce,
-                    32,
-                );
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("avx512f") {
+                check(&|buf, offset, id, start, n| unsafe { noncegen_avx512f(buf, offset, id, start, n) });
+            }
+            if is_x86_feature_detected!("avx2") {
+                check(&|buf, offset, id, start, n| unsafe { noncegen_avx2(buf, offset, id, start, n) });
+            }
+            if is_x86_feature_detected!("avx") {
+                check(&|buf, offset, id, start, n| unsafe { noncegen_avx(buf, offset, id, start, n) });
+            }
+            if is_x86_feature_detected!("sse2") {
+                check(&|buf, offset, id, start, n| unsafe { noncegen_sse2(buf, offset, id, start, n) });
             }
-            check_result(&buf);
         }
+        check(&noncegen_portable);
+        check(&noncegen_rust);
+        check(&noncegen_reference);
+    }
 
-        if is_x86_feature_detected!("sse2") {
+    #[test]
+    fn simd_backends() {
+        let available = available_simd();
+        assert_eq!(init_simd(), available[0]);
+        // the fallbacks run anywhere
+        assert_eq!(available[available.len() - 2..], [SimdExtension::Portable, SimdExtension::None]);
+        assert_eq!(SimdExtension::None.to_string(), "scalar");
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert_eq!(format!("{:<8}|", SimdExtension::AVX512f), "avx512f |");
+    }
+
+    #[test]
+    fn self_test() {
+        let reference = self_test_reference();
+        for simd_ext in available_simd() {
+            assert!(self_test_cpu(&simd_ext, &reference), "{} differs", simd_ext);
+        }
+        let mut broken = reference.clone();
+        broken[12345] ^= 1;
+        assert!(!self_test_cpu(&init_simd(), &broken));
+    }
+
+    #[cfg(feature = "c-simd")]
+    type CNoncegen = unsafe extern "C" fn(*mut libc::c_void, usize, usize, u64, u64, u64);
+
+    #[cfg(feature = "c-simd")]
+    #[test]
+    fn c_noncegen() {
+        let numeric_id = 7900104405094198526;
+        let start_nonce = 1337;
+        let noncegens: [(&str, bool, unsafe extern "C" fn(), CNoncegen); 4] = [
+            ("avx512f", is_x86_feature_detected!("avx512f"), c::init_shabal_avx512f, c::noncegen_avx512f),
+            ("avx2", is_x86_feature_detected!("avx2"), c::init_shabal_avx2, c::noncegen_avx2),
+            ("avx", is_x86_feature_detected!("avx"), c::init_shabal_avx, c::noncegen_avx),
+            ("sse2", is_x86_feature_detected!("sse2"), c::init_shabal_sse2, c::noncegen_sse2),
+        ];
+        let mut expected = vec![0; 32 * plotter::NONCE_SIZE as usize];
+        noncegen_rust(&mut expected, 0, numeric_id, start_nonce, 32);
+        for (name, detected, init, noncegen) in noncegens {
+            if !detected {
+                continue;
+            }
             let mut buf = vec![0; 32 * plotter::NONCE_SIZE as usize];
             unsafe {
-                init_shabal_sse2();
-                noncegen_sse2(
-                    buf.as_mut_ptr() as *mut c_void,
-                    32,
-                    0,
-                    numeric_id,
-                    start_nonce,
-                    32,
-                );
+                init();
+                noncegen(buf.as_mut_ptr() as *mut libc::c_void, 32, 0, numeric_id, start_nonce, 32);
             }
-            check_result(&buf);
+            assert!(buf == expected, "{} differs", name);
         }
-
-        let mut buf = vec![0; 32 * plotter::NONCE_SIZE as usize];
-        noncegen_rust(&mut buf, 0, numeric_id, start_nonce, 32);
-        check_result(&buf);
     }
-}
\ No newline at end of file
+}
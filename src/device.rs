@@ -0,0 +1,236 @@
+use crate::plotter::NONCE_SIZE;
+use crate::utils::{open, open_r};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Layout of a plot device (a whole block device, a partition or a regular
+// file standing in for one):
+//
+//   0             plot index, HEADER_SIZE bytes
+//   DATA_ALIGN    plot 0, nonces * NONCE_SIZE bytes, same layout as a plot file
+//   ...           plot 1, 2, ... each starting on the next DATA_ALIGN boundary
+//
+// The index starts with MAGIC, a u32 version and a u32 entry count, followed
+// by one entry per plot: numeric id, start nonce, nonces, byte offset of the
+// plot on the device and the number of nonces written so far (the resume
+// marker). All integers are little endian.
+
+const MAGIC: &[u8; 8] = b"ANNEPLOT";
+const VERSION: u32 = 1;
+pub const HEADER_SIZE: u64 = 4096;
+const ENTRY_SIZE: usize = 40;
+const MAX_ENTRIES: usize = (HEADER_SIZE as usize - 16) / ENTRY_SIZE;
+// plots start on 1 MiB boundaries, a multiple of every sector size
+const DATA_ALIGN: u64 = 1 << 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceEntry {
+    pub numeric_id: u64,
+    pub start_nonce: u64,
+    pub nonces: u64,
+    pub offset: u64,
+    pub progress: u64,
+}
+
+pub struct PlotDevice {
+    pub path: PathBuf,
+    pub size: u64,
+    pub entries: Vec<DeviceEntry>,
+    // false if the device doesn't carry a plot index yet
+    pub formatted: bool,
+}
+
+impl PlotDevice {
+    pub fn open(path: &Path) -> Result<PlotDevice, Error> {
+        let mut file = open_r(path)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "device too small"));
+        }
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        let entries = decode_index(&header)?;
+
+        Ok(PlotDevice {
+            path: path.to_path_buf(),
+            size,
+            formatted: entries.is_some(),
+            entries: entries.unwrap_or_default(),
+        })
+    }
+
+    /// Offset of the next plot added, the first DATA_ALIGN boundary behind
+    /// the last plot.
+    pub fn next_offset(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|x| x.offset + x.nonces * NONCE_SIZE)
+            .max()
+            .unwrap_or(0)
+            .max(HEADER_SIZE)
+            .div_ceil(DATA_ALIGN)
+            * DATA_ALIGN
+    }
+
+    pub fn free_space(&self) -> u64 {
+        if self.entries.len() == MAX_ENTRIES {
+            return 0;
+        }
+        self.size.saturating_sub(self.next_offset())
+    }
+
+    pub fn find(&self, numeric_id: u64, start_nonce: u64, nonces: u64) -> Option<usize> {
+        self.entries.iter().position(|x| {
+            x.numeric_id == numeric_id && x.start_nonce == start_nonce && x.nonces == nonces
+        })
+    }
+
+    /// Appends a plot to the index and returns its slot.
+    pub fn add_plot(&mut self, numeric_id: u64, start_nonce: u64, nonces: u64) -> Result<usize, Error> {
+        if self.entries.len() == MAX_ENTRIES {
+            return Err(Error::other("plot index full"));
+        }
+        let offset = self.next_offset();
+        if offset + nonces * NONCE_SIZE > self.size {
+            return Err(Error::other("insufficient space on device"));
+        }
+        self.entries.push(DeviceEntry {
+            numeric_id,
+            start_nonce,
+            nonces,
+            offset,
+            progress: 0,
+        });
+        self.write_index()?;
+        self.formatted = true;
+        Ok(self.entries.len() - 1)
+    }
+
+    /// The encoded plot index, written at offset 0 of the device.
+    pub fn index(&self) -> Vec<u8> {
+        encode_index(&self.entries)
+    }
+
+    fn write_index(&self) -> Result<(), Error> {
+        let mut file = open(&self.path)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&encode_index(&self.entries))?;
+        file.flush()
+    }
+}
+
+fn encode_index(entries: &[DeviceEntry]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for entry in entries {
+        for x in &[
+            entry.numeric_id,
+            entry.start_nonce,
+            entry.nonces,
+            entry.offset,
+            entry.progress,
+        ] {
+            header.extend_from_slice(&x.to_le_bytes());
+        }
+    }
+    header.resize(HEADER_SIZE as usize, 0);
+    header
+}
+
+// returns None for a device without plot index
+fn decode_index(header: &[u8]) -> Result<Option<Vec<DeviceEntry>>, Error> {
+    if &header[0..8] != MAGIC {
+        return Ok(None);
+    }
+    let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+    if u32_at(8) != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, "unsupported plot index version"));
+    }
+    let count = u32_at(12) as usize;
+    if count > MAX_ENTRIES {
+        return Err(Error::new(ErrorKind::InvalidData, "corrupted plot index"));
+    }
+    Ok(Some(
+        (0..count)
+            .map(|i| {
+                let base = 16 + i * ENTRY_SIZE;
+                DeviceEntry {
+                    numeric_id: u64_at(base),
+                    start_nonce: u64_at(base + 8),
+                    nonces: u64_at(base + 16),
+                    offset: u64_at(base + 24),
+                    progress: u64_at(base + 32),
+                }
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::ScratchPath;
+    use crate::writer::PlotFile;
+    use std::fs;
+
+    fn fake_device(name: &str, size: u64) -> ScratchPath {
+        let path = ScratchPath::temp(&format!("device-{}", name));
+        let file = fs::File::create(&path).unwrap();
+        file.set_len(size).unwrap();
+        path
+    }
+
+    #[test]
+    fn plot_index() {
+        let path = fake_device("index", 64 * DATA_ALIGN);
+        let mut device = PlotDevice::open(&path).unwrap();
+        assert!(!device.formatted);
+        assert_eq!(device.free_space(), 63 * DATA_ALIGN);
+
+        let first = device.add_plot(7, 0, 10).unwrap();
+        let second = device.add_plot(7, 10, 20).unwrap();
+        device.entries[second].progress = 8;
+        device.write_index().unwrap();
+
+        let device = PlotDevice::open(&path).unwrap();
+        assert!(device.formatted);
+        assert_eq!(device.find(7, 10, 20), Some(second));
+        assert_eq!(device.entries[first].offset, DATA_ALIGN);
+        assert_eq!(device.entries[second].offset, 4 * DATA_ALIGN);
+        assert_eq!(device.entries[second].progress, 8);
+        assert_eq!(device.free_space(), 64 * DATA_ALIGN - 9 * DATA_ALIGN);
+    }
+
+    #[test]
+    fn write_with_base_offset() {
+        let path = fake_device("write", 8 * DATA_ALIGN);
+        let mut device = PlotDevice::open(&path).unwrap();
+        let slot = device.add_plot(1, 0, 4).unwrap();
+        let offset = device.entries[slot].offset;
+
+        // 4 nonce plot written from a 2 nonce buffer, every scoop entry
+        // holds its nonce number in its first byte
+        let mut file = PlotFile::open(&path, false, offset, 0).unwrap();
+        for written in [0u64, 2] {
+            let mut bs = vec![0u8; 2 * NONCE_SIZE as usize];
+            for scoop in 0..4096 {
+                for n in 0..2 {
+                    bs[(scoop * 2 + n) * 64] = (written as usize + n) as u8;
+                }
+            }
+            file.write_buffer(&bs, 4, written, 2, &None).unwrap();
+        }
+
+        let data = fs::read(&path).unwrap();
+        for scoop in 0..4096u64 {
+            for n in 0..4u64 {
+                assert_eq!(data[(offset + (scoop * 4 + n) * 64) as usize], n as u8);
+            }
+        }
+        assert!(data[..HEADER_SIZE as usize].starts_with(MAGIC));
+    }
+}
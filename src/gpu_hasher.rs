@@ -1,5 +1,6 @@
 use crate::cpu_hasher::SafePointer;
 use crate::ocl::{gpu_hash, gpu_hash_and_transfer_to_host, gpu_transfer_to_host, GpuContext};
+use crate::scheduler::WorkerMessage;
 use crossbeam_channel::Receiver;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
@@ -14,9 +15,9 @@ pub struct GpuTask {
 }
 
 pub fn create_gpu_hasher_thread(
-    gpu_id: u8,
+    gpu_id: usize,
     gpu_context: Arc<Mutex<GpuContext>>,
-    tx: Sender<(u8, u8, u64)>,
+    tx: Sender<WorkerMessage>,
     rx_hasher_task: Receiver<Option<GpuTask>>,
 ) -> impl FnOnce() {
     move || {
@@ -42,7 +43,7 @@ pub fn create_gpu_hasher_thread(
                             gpu_hash(&gpu_context, &task);
                             buffer_id = 1 - buffer_id;
                             last_task = task;
-                            tx.send((gpu_id, 1u8, 0))
+                            tx.send(WorkerMessage::Ready(gpu_id))
                                 .expect("GPU task can't communicate with scheduler thread.");
                         }
                     // last run - just transfer
@@ -50,16 +51,16 @@ pub fn create_gpu_hasher_thread(
                         gpu_transfer_to_host(&gpu_context, buffer_id, &last_task);
                         first_run = true;
                         buffer_id = 0;
-                        tx.send((gpu_id, 0u8, last_task.local_nonces))
+                        tx.send(WorkerMessage::Hashed(gpu_id, last_task.local_nonces))
                             .expect("GPU task can't communicate with scheduler thread.");
                     // normal run - hash and transfer async
                     } else {
                         gpu_hash_and_transfer_to_host(&gpu_context, buffer_id, &task, &last_task);
                         buffer_id = 1 - buffer_id;
-                        tx.send((gpu_id, 0u8, last_task.local_nonces))
+                        tx.send(WorkerMessage::Hashed(gpu_id, last_task.local_nonces))
                             .expect("GPU task can't communicate with scheduler thread.");
                         last_task = task;
-                        tx.send((gpu_id, 1u8, 0))
+                        tx.send(WorkerMessage::Ready(gpu_id))
                             .expect("GPU task can't communicate with scheduler thread.");
                     }
                 }
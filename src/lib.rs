@@ -0,0 +1,5 @@
+//! Shabal-256 as the plotter uses it, for miners and verifiers: the scalar
+//! hash in `shabal256` and the multi-lane one in `mshabal`.
+
+pub mod mshabal;
+pub mod shabal256;
@@ -1,22 +1,38 @@
+mod cgroup;
 mod cpu_hasher;
+mod device;
 #[cfg(feature = "opencl")]
 mod gpu_hasher;
 #[cfg(feature = "opencl")]
 mod ocl;
+mod numa;
+mod plan;
 mod plotter;
 mod poc_hashing;
 mod scheduler;
-mod shabal256;
+mod throttle;
+#[cfg(target_os = "linux")]
+mod uring;
 mod utils;
 mod writer;
 mod buffer;
 
 use std::cmp::min;
+use std::path::Path;
 use std::process;
+use std::time::Duration;
 
+use clap::builder::TypedValueParser;
 use clap::{Arg, ArgAction, ArgGroup, Command};
+use crate::buffer::HugePages;
+use crate::cgroup::CgroupLimits;
+use crate::cpu_hasher::{available_simd, bench_cpu};
+use crate::device::PlotDevice;
+use crate::numa::{format_cpu_list, parse_cpu_list, select_cpus};
+use crate::plan::PlotPlan;
 use crate::plotter::{Plotter, PlotterTask};
-use crate::utils::set_low_prio;
+use crate::utils::{set_low_prio, Prealloc};
+use crate::writer::MmapAdvice;
 
 fn main() {
     let mut cmd = Command::new("anne-plotter")
@@ -33,6 +49,14 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .global(true),
         )
+        .arg(
+            Arg::new("sector_size")
+                .long("sector-size")
+                .value_name("BYTES")
+                .help("Sector size used to align direct i/o, overrides detection (power of two, 512 to 65536)")
+                .value_parser(parse_sector_size)
+                .global(true),
+        )
         .arg(
             Arg::new("disable_async_io")
                 .short('a')
@@ -41,6 +65,127 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .global(true),
         )
+        .arg(
+            Arg::new("disable_io_uring")
+                .long("no-uring")
+                .help("Disables the io_uring writer (linux only), uses blocking writes instead")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("mmap")
+                .long("mmap")
+                .value_name("ADVICE")
+                .help("Writes through a memory mapping of the plot file instead of direct i/o, ADVICE (default random) is passed to madvise, --sync-interval controls msync (optional)")
+                .num_args(0..=1)
+                .default_missing_value("random")
+                .value_parser(
+                    clap::builder::PossibleValuesParser::new(["normal", "random", "sequential"])
+                        .map(|x| x.parse::<MmapAdvice>().unwrap()),
+                )
+                .global(true),
+        )
+        .arg(
+            Arg::new("queue_depth")
+                .long("qd")
+                .value_name("DEPTH")
+                .help("io_uring queue depth: max. scoop writes in flight (optional)")
+                .value_parser(clap::value_parser!(u32).range(1..=32768))
+                .default_value("64"),
+        )
+        .arg(
+            Arg::new("verify_writes")
+                .long("verify-writes")
+                .value_name("SCOOPS")
+                .help("Re-reads SCOOPS scoop regions (default 16, all 4096) of every written buffer with direct i/o and compares them, rewrites the buffer on mismatch (optional)")
+                .num_args(0..=1)
+                .default_missing_value("16")
+                .value_parser(clap::value_parser!(u64).range(1..=4096))
+                .global(true),
+        )
+        .arg(
+            Arg::new("sync_interval")
+                .long("sync-interval")
+                .value_name("BUFFERS")
+                .help("Flushes written data and then the resume marker every BUFFERS buffers, 0 never flushes (unsafe on power loss) (optional)")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1")
+                .global(true),
+        )
+        .arg(
+            Arg::new("write_retries")
+                .long("write-retries")
+                .value_name("N")
+                .help("Repeats a failed write up to N times before plotting is aborted (optional)")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("3")
+                .global(true),
+        )
+        .arg(
+            Arg::new("retry_delay")
+                .long("retry-delay")
+                .value_name("MS")
+                .help("Milliseconds to wait before repeating a failed write, doubled for each further attempt (optional)")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("500")
+                .global(true),
+        )
+        .arg(
+            Arg::new("max_write_rate")
+                .long("max-write-rate")
+                .value_name("RATE")
+                .help("Limits the write rate per disk, e.g. 80MiB/s (optional)")
+                .value_parser(throttle::parse_rate)
+                .global(true),
+        )
+        .arg(
+            Arg::new("write_rate_file")
+                .long("write-rate-file")
+                .value_name("FILE")
+                .help("Takes the write rate limit from FILE (e.g. 80MiB/s, 0 = unlimited), re-read every second to change it while plotting (optional)")
+                .global(true),
+        )
+        .arg(
+            Arg::new("prealloc")
+                .long("prealloc")
+                .value_name("STRATEGY")
+                .help("How new plot files are preallocated, auto uses fallocate and falls back to zero-fill where it isn't supported")
+                .value_parser(
+                    clap::builder::PossibleValuesParser::new(["fallocate", "sparse", "zero-fill", "auto"])
+                        .map(|x| x.parse::<Prealloc>().unwrap()),
+                )
+                .default_value("auto")
+                .global(true),
+        )
+        .arg(
+            Arg::new("huge_pages")
+                .long("huge-pages")
+                .value_name("SIZE")
+                .help("Backs the plotting buffers with huge pages: thp (default) uses transparent huge pages, 2m and 1g explicit ones from the hugetlb pool. Falls back to smaller pages if unavailable (optional)")
+                .num_args(0..=1)
+                .default_missing_value("thp")
+                .default_value("off")
+                .value_parser(
+                    clap::builder::PossibleValuesParser::new(["off", "thp", "2m", "1g"])
+                        .map(|x| x.parse::<HugePages>().unwrap()),
+                )
+                .global(true),
+        )
+        .arg(
+            Arg::new("mlock")
+                .long("mlock")
+                .help("Locks the plotting buffers in RAM so they can't be swapped out, raises RLIMIT_MEMLOCK as far as permitted and limits memory usage to it (optional)")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("buffers")
+                .long("buffers")
+                .value_name("N")
+                .help("Number of RAM buffers to split memory into, default 2 (optional)")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .conflicts_with("disable_async_io"),
+        )
         .arg(
             Arg::new("low_priority")
                 .short('l')
@@ -65,6 +210,45 @@ fn main() {
                 .action(ArgAction::SetTrue)
                 .global(true),
         )
+        .arg(
+            Arg::new("no_self_test")
+                .long("no-self-test")
+                .help("Skips hashing a few test nonces with the selected backend and gpus before plotting")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("all_simd")
+                .long("all-simd")
+                .help("Benchmarks hashing with every SIMD backend the cpu supports")
+                .action(ArgAction::SetTrue)
+                .requires("benchmark")
+                .global(true),
+        )
+        .arg(
+            Arg::new("simd")
+                .long("simd")
+                .value_name("BACKEND")
+                .help("Forces the SIMD backend used for hashing, auto (default) picks the widest the cpu supports")
+                .default_value("auto")
+                .value_parser(["auto", "avx512f", "avx2", "avx", "sse2", "portable", "scalar"])
+                .global(true),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Runs all pre-flight checks and reports what would be plotted, without writing anything")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Reports the --dry-run plan as json (one object per plot file)")
+                .action(ArgAction::SetTrue)
+                .requires("dry_run")
+                .global(true),
+        )
         .arg(
             Arg::new("numeric_id")
                 .short('i')
@@ -110,6 +294,13 @@ fn main() {
                 .value_name("PATH")
                 .help("Target path for plotfile (optional)"),
         )
+        .arg(
+            Arg::new("device")
+                .long("device")
+                .value_name("DEVICE")
+                .help("Plots onto a block device or partition instead of into files. Plots are laid out behind a small index at the start of the device. Existing data on the device is overwritten!")
+                .conflicts_with("path"),
+        )
         .arg(
             Arg::new("memory")
                 .short('m')
@@ -124,7 +315,28 @@ fn main() {
                 .long("cpu")
                 .value_name("THREADS")
                 .help("Maximum cpu cores you want to use (optional)")
-                .value_parser(clap::value_parser!(u8)),
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("cpu_list")
+                .long("cpu-list")
+                .value_name("CPUS")
+                .help("Cpus for hashing threads, e.g. 0-7,16-23 (optional)")
+                .value_parser(parse_cpu_list),
+        )
+        .arg(
+            Arg::new("io_cpu_list")
+                .long("io-cpu-list")
+                .value_name("CPUS")
+                .help("Cpus for writer and scheduler threads (optional)")
+                .value_parser(parse_cpu_list),
+        )
+        .arg(
+            Arg::new("exclude_cpus")
+                .long("exclude-cpus")
+                .value_name("CPUS")
+                .help("Cpus to keep free of plotter threads, e.g. for a miner (optional)")
+                .value_parser(parse_cpu_list),
         )
         .arg(
             Arg::new("gpu")
@@ -173,6 +385,10 @@ fn main() {
         return;
     }
 
+    let dry_run = matches.get_flag("dry_run");
+    let json = matches.get_flag("json");
+    let quiet = matches.get_flag("quiet") || json;
+
     let numeric_id = *matches.get_one::<u64>("numeric_id").expect("numeric_id required");
 
     let nonces = *matches.get_one::<u64>("nonces").expect("nonces required");
@@ -188,17 +404,72 @@ fn main() {
                 .unwrap()
         });
 
+    let device = matches.get_one::<String>("device").cloned();
+
     let mem = matches.get_one::<String>("memory").cloned().unwrap();
 
-    let cpu_threads_input = matches.get_one::<u8>("cpu").copied().unwrap_or(0);
+    let buffers = match matches.get_one::<u64>("buffers") {
+        Some(&x) => x,
+        None if matches.get_flag("disable_async_io") => 1,
+        None => 2,
+    };
+
+    let queue_depth = *matches.get_one::<u32>("queue_depth").unwrap();
+
+    let verify_scoops = matches.get_one::<u64>("verify_writes").copied().unwrap_or(0);
+    let mmap = matches.get_one::<MmapAdvice>("mmap").copied();
+    let write_retries = *matches.get_one::<u32>("write_retries").unwrap();
+    let retry_delay = Duration::from_millis(*matches.get_one::<u64>("retry_delay").unwrap());
+    let huge_pages = *matches.get_one::<HugePages>("huge_pages").unwrap();
+
+    let sync_interval = *matches.get_one::<u64>("sync_interval").unwrap();
+
+    let max_write_rate = matches.get_one::<u64>("max_write_rate").copied().unwrap_or(0);
+    let write_rate_file = matches.get_one::<String>("write_rate_file").cloned();
+
+    let prealloc = *matches.get_one::<Prealloc>("prealloc").unwrap();
+
+    let cpu_threads_input = matches.get_one::<usize>("cpu").copied().unwrap_or(0);
 
     // Fixed type inference
     let gpus: Option<Vec<String>> = matches
         .get_many::<String>("gpu")
         .map(|v| v.cloned().collect());
 
-    // CPU thread calculation
-    let cores = sys_info::cpu_num().unwrap() as u8;
+    let cpu_list = matches.get_one::<Vec<usize>>("cpu_list").cloned();
+    let io_cpu_list = matches.get_one::<Vec<usize>>("io_cpu_list").cloned();
+    let exclude_cpus = matches.get_one::<Vec<usize>>("exclude_cpus").cloned().unwrap_or_default();
+
+    // CPU thread calculation, within the limits of a container and the cpus
+    // chosen for hashing
+    let limits = CgroupLimits::read(Path::new("/"));
+    let usable = limits.usable_cpus();
+    for list in [&cpu_list, &io_cpu_list].into_iter().flatten() {
+        let unusable: Vec<usize> = list.iter().copied().filter(|x| !usable.contains(x)).collect();
+        if !quiet && !unusable.is_empty() {
+            println!("Warning: cpus {} aren't usable and are left out.", format_cpu_list(&unusable));
+        }
+    }
+    let hashing_cpus = select_cpus(&usable, cpu_list.as_deref(), &exclude_cpus);
+    let io_cpus = select_cpus(&usable, io_cpu_list.as_deref(), &exclude_cpus);
+    if hashing_cpus.is_empty() || io_cpus.is_empty() {
+        eprintln!("Error: no usable cpus left, usable cpus are {}", format_cpu_list(&usable));
+        process::exit(1);
+    }
+    let available = available_simd();
+    let simd = match matches.get_one::<String>("simd").unwrap().as_str() {
+        "auto" => None,
+        name => match available.iter().find(|x| x.to_string() == name) {
+            Some(x) => Some(x.clone()),
+            None => {
+                let names: Vec<String> = available.iter().map(|x| x.to_string()).collect();
+                eprintln!("Error: --simd {} isn't supported by this cpu, available are {}", name, names.join(", "));
+                process::exit(1);
+            }
+        },
+    };
+
+    let cores = limits.cpu_count(&hashing_cpus);
     let mut cpu_threads = if cpu_threads_input == 0 {
         cores
     } else {
@@ -212,115 +483,212 @@ fn main() {
 
     let p = Plotter::new();
 
+    // the hashing rate of dry-run plans, benchmarked once for all of them.
+    // Gpus aren't benchmarked, a cpu-only rate would be misleading then.
+    let gpu_plotting = gpus.is_some();
+    let mut cpu_rate = None;
+    let mut nonces_per_minute = |plan: &PlotPlan| {
+        if gpu_plotting {
+            return None;
+        }
+        Some(*cpu_rate.get_or_insert_with(|| bench_cpu(plan.cpu_threads, &plan.simd_ext)))
+    };
+
     if let Some(&auto_count) = matches.get_one::<u64>("start_nonce_auto") {
         if auto_count == 0 {
             eprintln!("Error: --sna count must be >= 1");
             process::exit(1);
         }
 
-        if !matches.get_flag("quiet") {
+        if !quiet {
             println!("--sna enabled: plotting {auto_count} sequential file(s)");
         }
 
-        let mut current_start = 0u64;
         let mut actual_nonces_per_file: u64 = nonces;  // fallback
 
-        // First: scan for existing files to find where to continue
-        if let Ok(entries) = std::fs::read_dir(&output_path) {
-            let mut max_end: u64 = 0;
-            let prefix = format!("{}_", numeric_id);
-
-            for entry in entries.flatten() {
-                if let Some(file_name) = entry.file_name().to_str() {
-                    if file_name.starts_with(&prefix) {
-                        let parts: Vec<&str> = file_name.split('_').collect();
-                        if parts.len() >= 3 {
-                            if let (Ok(sn), Ok(cnt)) = (parts[1].parse::<u64>(), parts[2].parse::<u64>()) {
-                                let end = sn + cnt;
-                                if end > max_end {
-                                    max_end = end;
-                                    actual_nonces_per_file = cnt;  // Use real plotted count
-                                }
-                            }
-                        }
-                    }
-                }
+        // First: scan for existing plots to find where to continue
+        let mut max_end: u64 = 0;
+        for (sn, cnt) in existing_plots(&output_path, &device, numeric_id) {
+            let end = sn + cnt;
+            if end > max_end {
+                max_end = end;
+                actual_nonces_per_file = cnt;  // Use real plotted count
             }
-            current_start = max_end;
         }
+        let current_start = max_end;
 
-        if !matches.get_flag("quiet") {
+        if !quiet {
             println!("Starting from nonce {current_start}");
             if actual_nonces_per_file != nonces {
-                println!("Detected aligned nonce count per file: {actual_nonces_per_file} (from existing file)");
+                println!("Detected nonce count per file: {actual_nonces_per_file} (from existing file)");
             }
         }
 
+        // the files a dry run plans aren't there to take up their space
+        let mut reserved_space = 0;
+
         // Now plot each file one by one
         for i in 0..auto_count {
             let this_start = current_start + i * actual_nonces_per_file;
 
-            if !matches.get_flag("quiet") {
+            if !quiet {
                 println!("\n--- Plotting file {} of {auto_count}: start_nonce = {this_start} ---", i + 1);
             }
 
             let file_task = PlotterTask {
                 numeric_id,
                 start_nonce: this_start,
-                nonces,  // pass original — plotter will round it down if needed
+                nonces,
                 output_path: output_path.clone(),
+                device: device.clone(),
                 mem: mem.clone(),
                 cpu_threads,
                 gpus: gpus.clone(),
-                direct_io: !matches.get_flag("disable_direct_io"),
-                async_io: !matches.get_flag("disable_async_io"),
-                quiet: matches.get_flag("quiet"),
+                direct_io: !matches.get_flag("disable_direct_io") && mmap.is_none(),
+                sector_size: matches.get_one::<u64>("sector_size").copied(),
+                buffers,
+                quiet,
                 benchmark: matches.get_flag("benchmark"),
                 zcb: matches.get_flag("zero_copy"),
+                dry_run,
+                io_uring: !matches.get_flag("disable_io_uring"),
+                queue_depth,
+                verify_scoops,
+                sync_interval,
+                max_write_rate,
+                write_rate_file: write_rate_file.clone(),
+                prealloc,
+                mmap,
+                write_retries,
+                retry_delay,
+                huge_pages,
+                mlock: matches.get_flag("mlock"),
+                cpu_list: cpu_list.clone(),
+                io_cpu_list: io_cpu_list.clone(),
+                exclude_cpus: exclude_cpus.clone(),
+                simd: simd.clone(),
+                all_simd: matches.get_flag("all_simd"),
+                self_test: !matches.get_flag("no_self_test"),
             };
 
-            p.run(file_task);
-
-            // After plotting this file, update actual_nonces_per_file from the new filename
-            // (in case alignment changed or first file)
-            if let Ok(entries) = std::fs::read_dir(&output_path) {
-                let mut latest_cnt: u64 = actual_nonces_per_file;
-                let prefix = format!("{}_", numeric_id);
-
-                for entry in entries.flatten() {
-                    if let Some(file_name) = entry.file_name().to_str() {
-                        if file_name.starts_with(&prefix) {
-                            let parts: Vec<&str> = file_name.split('_').collect();
-                            if parts.len() >= 3 {
-                                if let (Ok(sn), Ok(cnt)) = (parts[1].parse::<u64>(), parts[2].parse::<u64>()) {
-                                    if sn == this_start {
-                                        latest_cnt = cnt;
-                                        break;
-                                    }
-                                }
-                            }
+            // A dry run creates no file, take the nonce count from the plan instead
+            if dry_run {
+                let mut file_task = file_task;
+                match p.plan(&mut file_task, reserved_space) {
+                    Some(plan) => {
+                        plan.report(json, nonces_per_minute(&plan));
+                        actual_nonces_per_file = plan.nonces;
+                        if !plan.file_exists {
+                            reserved_space += plan.plotsize;
                         }
                     }
+                    None => return,
                 }
-                actual_nonces_per_file = latest_cnt;
+                continue;
+            }
+
+            if let Err(e) = p.run(file_task) {
+                eprintln!("\nError: {}", e);
+                process::exit(1);
+            }
+
+            // After plotting this file, update actual_nonces_per_file from the new plot
+            // (in case alignment changed or first file)
+            if let Some((_, cnt)) = existing_plots(&output_path, &device, numeric_id)
+                .into_iter()
+                .find(|&(sn, _)| sn == this_start)
+            {
+                actual_nonces_per_file = cnt;
             }
         }
     } else {
         let start_nonce = *matches.get_one::<u64>("start_nonce").expect("--sn is required when not using --sna");
 
-        p.run(PlotterTask {
+        let mut task = PlotterTask {
             numeric_id,
             start_nonce,
             nonces,
             output_path,
+            device,
             mem,
             cpu_threads,
             gpus,
-            direct_io: !matches.get_flag("disable_direct_io"),
-            async_io: !matches.get_flag("disable_async_io"),
-            quiet: matches.get_flag("quiet"),
+            direct_io: !matches.get_flag("disable_direct_io") && mmap.is_none(),
+            sector_size: matches.get_one::<u64>("sector_size").copied(),
+            buffers,
+            quiet,
             benchmark: matches.get_flag("benchmark"),
             zcb: matches.get_flag("zero_copy"),
-        });
+            dry_run,
+            io_uring: !matches.get_flag("disable_io_uring"),
+            queue_depth,
+            verify_scoops,
+            sync_interval,
+            max_write_rate,
+            write_rate_file,
+            prealloc,
+            mmap,
+            write_retries,
+            retry_delay,
+            huge_pages,
+            mlock: matches.get_flag("mlock"),
+            cpu_list,
+            io_cpu_list,
+            exclude_cpus,
+            simd,
+            all_simd: matches.get_flag("all_simd"),
+            self_test: !matches.get_flag("no_self_test"),
+        };
+
+        if dry_run {
+            if let Some(plan) = p.plan(&mut task, 0) {
+                plan.report(json, nonces_per_minute(&plan));
+            }
+        } else {
+            if let Err(e) = p.run(task) {
+                eprintln!("\nError: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+fn parse_sector_size(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(x) if x.is_power_of_two() && (512..=65536).contains(&x) => Ok(x),
+        _ => Err("expected a power of two from 512 to 65536".to_string()),
+    }
+}
+
+// (start nonce, nonces) of all plots of an account in the output path or on the plot device
+fn existing_plots(output_path: &str, device: &Option<String>, numeric_id: u64) -> Vec<(u64, u64)> {
+    if let Some(device) = device {
+        return match PlotDevice::open(Path::new(device)) {
+            Ok(x) => x
+                .entries
+                .iter()
+                .filter(|x| x.numeric_id == numeric_id)
+                .map(|x| (x.start_nonce, x.nonces))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+    }
+
+    let mut plots = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(output_path) {
+        let prefix = format!("{}_", numeric_id);
+
+        for entry in entries.flatten() {
+            if let Some(file_name) = entry.file_name().to_str() {
+                if file_name.starts_with(&prefix) {
+                    let parts: Vec<&str> = file_name.split('_').collect();
+                    if parts.len() >= 3 {
+                        if let (Ok(sn), Ok(cnt)) = (parts[1].parse::<u64>(), parts[2].parse::<u64>()) {
+                            plots.push((sn, cnt));
+                        }
+                    }
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+    plots
+}
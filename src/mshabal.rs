@@ -0,0 +1,534 @@
+use crate::shabal256::{A_INIT, B_INIT, C_INIT};
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// A SIMD vector of 32 bit lanes with the operations Shabal needs. Each
+/// lane hashes a message of its own.
+///
+/// # Safety
+///
+/// The methods of the std::arch vectors use the instructions of their
+/// extension, they may only be called where the cpu supports it, i.e. from
+/// functions compiled with the matching `#[target_feature]`. `Portable`
+/// runs anywhere.
+// the methods share the safety section of the trait
+#[allow(clippy::missing_safety_doc)]
+pub trait Lanes: Copy {
+    const LANES: usize;
+    unsafe fn splat(x: u32) -> Self;
+    // the first LANES words of `x`
+    unsafe fn from_slice(x: &[u32]) -> Self;
+    unsafe fn write_to(self, x: &mut [u32]);
+    unsafe fn add(self, x: Self) -> Self;
+    unsafe fn sub(self, x: Self) -> Self;
+    unsafe fn xor(self, x: Self) -> Self;
+    // !self & x
+    unsafe fn andnot(self, x: Self) -> Self;
+    unsafe fn rotl1(self) -> Self;
+    unsafe fn rotl15(self) -> Self;
+    unsafe fn rotl17(self) -> Self;
+    unsafe fn mul3(self) -> Self;
+    unsafe fn mul5(self) -> Self;
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+macro_rules! impl_lanes {
+    ($t:ty, $lanes:expr, $set1:ident, $loadu:ident, $storeu:ident, $add:ident, $sub:ident,
+     $xor:ident, $or:ident, $andnot:ident, $slli:ident, $srli:ident) => {
+        impl Lanes for $t {
+            const LANES: usize = $lanes;
+            #[inline(always)]
+            unsafe fn splat(x: u32) -> Self {
+                unsafe { $set1(x as i32) }
+            }
+            #[inline(always)]
+            unsafe fn from_slice(x: &[u32]) -> Self {
+                assert!(x.len() >= $lanes);
+                unsafe { $loadu(x.as_ptr() as *const _) }
+            }
+            #[inline(always)]
+            unsafe fn write_to(self, x: &mut [u32]) {
+                assert!(x.len() >= $lanes);
+                unsafe { $storeu(x.as_mut_ptr() as *mut _, self) }
+            }
+            #[inline(always)]
+            unsafe fn add(self, x: Self) -> Self {
+                unsafe { $add(self, x) }
+            }
+            #[inline(always)]
+            unsafe fn sub(self, x: Self) -> Self {
+                unsafe { $sub(self, x) }
+            }
+            #[inline(always)]
+            unsafe fn xor(self, x: Self) -> Self {
+                unsafe { $xor(self, x) }
+            }
+            #[inline(always)]
+            unsafe fn andnot(self, x: Self) -> Self {
+                unsafe { $andnot(self, x) }
+            }
+            #[inline(always)]
+            unsafe fn rotl1(self) -> Self {
+                unsafe { $or($slli::<1>(self), $srli::<31>(self)) }
+            }
+            #[inline(always)]
+            unsafe fn rotl15(self) -> Self {
+                unsafe { $or($slli::<15>(self), $srli::<17>(self)) }
+            }
+            #[inline(always)]
+            unsafe fn rotl17(self) -> Self {
+                unsafe { $or($slli::<17>(self), $srli::<15>(self)) }
+            }
+            #[inline(always)]
+            unsafe fn mul3(self) -> Self {
+                unsafe { $add($slli::<1>(self), self) }
+            }
+            #[inline(always)]
+            unsafe fn mul5(self) -> Self {
+                unsafe { $add($slli::<2>(self), self) }
+            }
+        }
+    };
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+// sse2 and avx share it, avx code is just encoded with vex
+impl_lanes!(__m128i, 4, _mm_set1_epi32, _mm_loadu_si128, _mm_storeu_si128, _mm_add_epi32, _mm_sub_epi32,
+    _mm_xor_si128, _mm_or_si128, _mm_andnot_si128, _mm_slli_epi32, _mm_srli_epi32);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl_lanes!(__m256i, 8, _mm256_set1_epi32, _mm256_loadu_si256, _mm256_storeu_si256, _mm256_add_epi32,
+    _mm256_sub_epi32, _mm256_xor_si256, _mm256_or_si256, _mm256_andnot_si256, _mm256_slli_epi32,
+    _mm256_srli_epi32);
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl_lanes!(__m512i, 16, _mm512_set1_epi32, _mm512_loadu_si512, _mm512_storeu_si512, _mm512_add_epi32,
+    _mm512_sub_epi32, _mm512_xor_si512, _mm512_or_si512, _mm512_andnot_si512, _mm512_slli_epi32,
+    _mm512_srli_epi32);
+
+/// Plain array of lanes for targets without a std::arch backend. The
+/// lane-wise loops are simple enough for the compiler to vectorise them
+/// with whatever the target has, e.g. two 128 bit registers on NEON.
+#[derive(Clone, Copy)]
+#[repr(align(32))]
+pub struct Portable<const N: usize>([u32; N]);
+
+impl<const N: usize> Portable<N> {
+    #[inline(always)]
+    fn map(self, f: impl Fn(u32) -> u32) -> Self {
+        Portable(self.0.map(f))
+    }
+
+    #[inline(always)]
+    fn zip(self, x: Self, f: impl Fn(u32, u32) -> u32) -> Self {
+        Portable(std::array::from_fn(|i| f(self.0[i], x.0[i])))
+    }
+}
+
+impl<const N: usize> Lanes for Portable<N> {
+    const LANES: usize = N;
+    #[inline(always)]
+    unsafe fn splat(x: u32) -> Self {
+        Portable([x; N])
+    }
+    #[inline(always)]
+    unsafe fn from_slice(x: &[u32]) -> Self {
+        Portable(x[..N].try_into().unwrap())
+    }
+    #[inline(always)]
+    unsafe fn write_to(self, x: &mut [u32]) {
+        x[..N].copy_from_slice(&self.0);
+    }
+    #[inline(always)]
+    unsafe fn add(self, x: Self) -> Self {
+        self.zip(x, u32::wrapping_add)
+    }
+    #[inline(always)]
+    unsafe fn sub(self, x: Self) -> Self {
+        self.zip(x, u32::wrapping_sub)
+    }
+    #[inline(always)]
+    unsafe fn xor(self, x: Self) -> Self {
+        self.zip(x, |a, b| a ^ b)
+    }
+    #[inline(always)]
+    unsafe fn andnot(self, x: Self) -> Self {
+        self.zip(x, |a, b| !a & b)
+    }
+    #[inline(always)]
+    unsafe fn rotl1(self) -> Self {
+        self.map(|a| a.rotate_left(1))
+    }
+    #[inline(always)]
+    unsafe fn rotl15(self) -> Self {
+        self.map(|a| a.rotate_left(15))
+    }
+    #[inline(always)]
+    unsafe fn rotl17(self) -> Self {
+        self.map(|a| a.rotate_left(17))
+    }
+    #[inline(always)]
+    unsafe fn mul3(self) -> Self {
+        self.map(|a| a.wrapping_mul(3))
+    }
+    #[inline(always)]
+    unsafe fn mul5(self) -> Self {
+        self.map(|a| a.wrapping_mul(5))
+    }
+}
+
+// a single lane, the scalar noncegen
+impl Lanes for u32 {
+    const LANES: usize = 1;
+    #[inline(always)]
+    unsafe fn splat(x: u32) -> Self {
+        x
+    }
+    #[inline(always)]
+    unsafe fn from_slice(x: &[u32]) -> Self {
+        x[0]
+    }
+    #[inline(always)]
+    unsafe fn write_to(self, x: &mut [u32]) {
+        x[0] = self;
+    }
+    #[inline(always)]
+    unsafe fn add(self, x: Self) -> Self {
+        self.wrapping_add(x)
+    }
+    #[inline(always)]
+    unsafe fn sub(self, x: Self) -> Self {
+        self.wrapping_sub(x)
+    }
+    #[inline(always)]
+    unsafe fn xor(self, x: Self) -> Self {
+        self ^ x
+    }
+    #[inline(always)]
+    unsafe fn andnot(self, x: Self) -> Self {
+        !self & x
+    }
+    #[inline(always)]
+    unsafe fn rotl1(self) -> Self {
+        self.rotate_left(1)
+    }
+    #[inline(always)]
+    unsafe fn rotl15(self) -> Self {
+        self.rotate_left(15)
+    }
+    #[inline(always)]
+    unsafe fn rotl17(self) -> Self {
+        self.rotate_left(17)
+    }
+    #[inline(always)]
+    unsafe fn mul3(self) -> Self {
+        self.wrapping_mul(3)
+    }
+    #[inline(always)]
+    unsafe fn mul5(self) -> Self {
+        self.wrapping_mul(5)
+    }
+}
+
+/// `shabal256_fast` of LANES messages at once. Messages are interleaved by
+/// 32 bit word, `data` holds their whole 64 byte blocks, `term` their last
+/// block. The 8 words of each hash are written to `dst`, interleaved too.
+///
+/// # Safety
+///
+/// See `Lanes`.
+#[inline(always)]
+pub unsafe fn mshabal256_fast<V: Lanes>(data: &[V], term: &[V; 16], dst: &mut [V]) {
+    unsafe {
+        let mut a = A_INIT.map(|x| V::splat(x));
+        let mut b = B_INIT.map(|x| V::splat(x));
+        let mut c = C_INIT.map(|x| V::splat(x));
+        let mut w = 1u64;
+
+        for m in data.chunks_exact(16) {
+            let m: &[V; 16] = m.try_into().unwrap();
+            input_block_add(&mut b, m);
+            xor_w(&mut a, w);
+            apply_p(&mut a, &mut b, &c, m);
+            input_block_sub(&mut c, m);
+            std::mem::swap(&mut b, &mut c);
+            w += 1;
+        }
+        input_block_add(&mut b, term);
+        for i in 0..4 {
+            // 3 extra rounds after the last block
+            if i > 0 {
+                std::mem::swap(&mut b, &mut c);
+            }
+            xor_w(&mut a, w);
+            apply_p(&mut a, &mut b, &c, term);
+        }
+        dst[..8].copy_from_slice(&b[8..]);
+    }
+}
+
+/// Shabal-256 of N messages of the same length at once. 4, 8 and 16
+/// messages go to sse2, avx2 and avx512f if the cpu has it, other counts
+/// and cpus to `Portable`.
+pub fn shabal256_batch<const N: usize>(messages: &[&[u8]; N]) -> [[u8; 32]; N] {
+    assert!(
+        messages.iter().all(|x| x.len() == messages[0].len()),
+        "messages of a batch have to be of the same length"
+    );
+    let mut hashes = [[0u8; 32]; N];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if N == 16 && is_x86_feature_detected!("avx512f") {
+            unsafe { batch_avx512f(messages, &mut hashes) };
+            return hashes;
+        } else if N == 8 && is_x86_feature_detected!("avx2") {
+            unsafe { batch_avx2(messages, &mut hashes) };
+            return hashes;
+        } else if N == 4 && is_x86_feature_detected!("sse2") {
+            unsafe { batch_sse2(messages, &mut hashes) };
+            return hashes;
+        }
+    }
+    unsafe { batch::<Portable<N>>(messages, &mut hashes) };
+    hashes
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+fn batch_sse2(messages: &[&[u8]], hashes: &mut [[u8; 32]]) {
+    unsafe { batch::<__m128i>(messages, hashes) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+fn batch_avx2(messages: &[&[u8]], hashes: &mut [[u8; 32]]) {
+    unsafe { batch::<__m256i>(messages, hashes) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx512f")]
+fn batch_avx512f(messages: &[&[u8]], hashes: &mut [[u8; 32]]) {
+    unsafe { batch::<__m512i>(messages, hashes) }
+}
+
+// pads the messages, interleaves their words and hashes them, message k
+// on lane k
+#[inline(always)]
+unsafe fn batch<V: Lanes>(messages: &[&[u8]], hashes: &mut [[u8; 32]]) {
+    let blocks = messages[0].len() / 64;
+    let mut words = vec![0u32; V::LANES];
+    unsafe {
+        let mut data = Vec::with_capacity(blocks * 16);
+        let mut term = [V::splat(0); 16];
+        for i in 0..(blocks + 1) * 16 {
+            for (x, message) in words.iter_mut().zip(messages) {
+                *x = padded_word(message, i);
+            }
+            if i < blocks * 16 {
+                data.push(V::from_slice(&words));
+            } else {
+                term[i - blocks * 16] = V::from_slice(&words);
+            }
+        }
+        let mut dst = [V::splat(0); 8];
+        mshabal256_fast(&data, &term, &mut dst);
+        for (i, x) in dst.iter().enumerate() {
+            x.write_to(&mut words);
+            for (hash, x) in hashes.iter_mut().zip(&words) {
+                hash[i * 4..i * 4 + 4].copy_from_slice(&x.to_le_bytes());
+            }
+        }
+    }
+}
+
+// word i of a message padded with 0x80 and zeros
+fn padded_word(message: &[u8], i: usize) -> u32 {
+    let mut x = [0u8; 4];
+    for (j, x) in x.iter_mut().enumerate() {
+        *x = match message.get(i * 4 + j) {
+            Some(&b) => b,
+            None if i * 4 + j == message.len() => 0x80,
+            None => 0,
+        };
+    }
+    u32::from_le_bytes(x)
+}
+
+#[inline(always)]
+unsafe fn input_block_add<V: Lanes>(b: &mut [V; 16], m: &[V; 16]) {
+    for (b, m) in b.iter_mut().zip(m) {
+        *b = unsafe { b.add(*m) };
+    }
+}
+
+#[inline(always)]
+unsafe fn input_block_sub<V: Lanes>(c: &mut [V; 16], m: &[V; 16]) {
+    for (c, m) in c.iter_mut().zip(m) {
+        *c = unsafe { c.sub(*m) };
+    }
+}
+
+#[inline(always)]
+unsafe fn xor_w<V: Lanes>(a: &mut [V; 12], w: u64) {
+    unsafe {
+        a[0] = a[0].xor(V::splat(w as u32));
+        a[1] = a[1].xor(V::splat((w >> 32) as u32));
+    }
+}
+
+#[inline(always)]
+unsafe fn apply_p<V: Lanes>(a: &mut [V; 12], b: &mut [V; 16], c: &[V; 16], m: &[V; 16]) {
+    unsafe {
+        for b in b.iter_mut() {
+            *b = b.rotl17();
+        }
+        perm(a, b, c, m);
+        for (i, a) in a.iter_mut().enumerate() {
+            *a = a.add(c[(i + 11) % 16]).add(c[(i + 15) % 16]).add(c[(i + 3) % 16]);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[inline(always)]
+unsafe fn perm_elt<V: Lanes>(
+    a: &mut [V; 12],
+    b: &mut [V; 16],
+    xa0: usize,
+    xa1: usize,
+    xb0: usize,
+    xb1: usize,
+    xb2: usize,
+    xb3: usize,
+    xc: V,
+    xm: V,
+) {
+    unsafe {
+        let t = a[xa0].xor(a[xa1].rotl15().mul5()).xor(xc).mul3();
+        a[xa0] = t.xor(b[xb1]).xor(b[xb3].andnot(b[xb2])).xor(xm);
+        b[xb0] = b[xb0].rotl1().xor(a[xa0]).xor(V::splat(!0));
+    }
+}
+
+#[inline(always)]
+unsafe fn perm<V: Lanes>(a: &mut [V; 12], b: &mut [V; 16], c: &[V; 16], m: &[V; 16]) {
+    unsafe {
+        perm_elt(a, b, 0, 11, 0, 13, 9, 6, c[8], m[0]);
+        perm_elt(a, b, 1, 0, 1, 14, 10, 7, c[7], m[1]);
+        perm_elt(a, b, 2, 1, 2, 15, 11, 8, c[6], m[2]);
+        perm_elt(a, b, 3, 2, 3, 0, 12, 9, c[5], m[3]);
+        perm_elt(a, b, 4, 3, 4, 1, 13, 10, c[4], m[4]);
+        perm_elt(a, b, 5, 4, 5, 2, 14, 11, c[3], m[5]);
+        perm_elt(a, b, 6, 5, 6, 3, 15, 12, c[2], m[6]);
+        perm_elt(a, b, 7, 6, 7, 4, 0, 13, c[1], m[7]);
+        perm_elt(a, b, 8, 7, 8, 5, 1, 14, c[0], m[8]);
+        perm_elt(a, b, 9, 8, 9, 6, 2, 15, c[15], m[9]);
+        perm_elt(a, b, 10, 9, 10, 7, 3, 0, c[14], m[10]);
+        perm_elt(a, b, 11, 10, 11, 8, 4, 1, c[13], m[11]);
+        perm_elt(a, b, 0, 11, 12, 9, 5, 2, c[12], m[12]);
+        perm_elt(a, b, 1, 0, 13, 10, 6, 3, c[11], m[13]);
+        perm_elt(a, b, 2, 1, 14, 11, 7, 4, c[10], m[14]);
+        perm_elt(a, b, 3, 2, 15, 12, 8, 5, c[9], m[15]);
+        perm_elt(a, b, 4, 3, 0, 13, 9, 6, c[8], m[0]);
+        perm_elt(a, b, 5, 4, 1, 14, 10, 7, c[7], m[1]);
+        perm_elt(a, b, 6, 5, 2, 15, 11, 8, c[6], m[2]);
+        perm_elt(a, b, 7, 6, 3, 0, 12, 9, c[5], m[3]);
+        perm_elt(a, b, 8, 7, 4, 1, 13, 10, c[4], m[4]);
+        perm_elt(a, b, 9, 8, 5, 2, 14, 11, c[3], m[5]);
+        perm_elt(a, b, 10, 9, 6, 3, 15, 12, c[2], m[6]);
+        perm_elt(a, b, 11, 10, 7, 4, 0, 13, c[1], m[7]);
+        perm_elt(a, b, 0, 11, 8, 5, 1, 14, c[0], m[8]);
+        perm_elt(a, b, 1, 0, 9, 6, 2, 15, c[15], m[9]);
+        perm_elt(a, b, 2, 1, 10, 7, 3, 0, c[14], m[10]);
+        perm_elt(a, b, 3, 2, 11, 8, 4, 1, c[13], m[11]);
+        perm_elt(a, b, 4, 3, 12, 9, 5, 2, c[12], m[12]);
+        perm_elt(a, b, 5, 4, 13, 10, 6, 3, c[11], m[13]);
+        perm_elt(a, b, 6, 5, 14, 11, 7, 4, c[10], m[14]);
+        perm_elt(a, b, 7, 6, 15, 12, 8, 5, c[9], m[15]);
+        perm_elt(a, b, 8, 7, 0, 13, 9, 6, c[8], m[0]);
+        perm_elt(a, b, 9, 8, 1, 14, 10, 7, c[7], m[1]);
+        perm_elt(a, b, 10, 9, 2, 15, 11, 8, c[6], m[2]);
+        perm_elt(a, b, 11, 10, 3, 0, 12, 9, c[5], m[3]);
+        perm_elt(a, b, 0, 11, 4, 1, 13, 10, c[4], m[4]);
+        perm_elt(a, b, 1, 0, 5, 2, 14, 11, c[3], m[5]);
+        perm_elt(a, b, 2, 1, 6, 3, 15, 12, c[2], m[6]);
+        perm_elt(a, b, 3, 2, 7, 4, 0, 13, c[1], m[7]);
+        perm_elt(a, b, 4, 3, 8, 5, 1, 14, c[0], m[8]);
+        perm_elt(a, b, 5, 4, 9, 6, 2, 15, c[15], m[9]);
+        perm_elt(a, b, 6, 5, 10, 7, 3, 0, c[14], m[10]);
+        perm_elt(a, b, 7, 6, 11, 8, 4, 1, c[13], m[11]);
+        perm_elt(a, b, 8, 7, 12, 9, 5, 2, c[12], m[12]);
+        perm_elt(a, b, 9, 8, 13, 10, 6, 3, c[11], m[13]);
+        perm_elt(a, b, 10, 9, 14, 11, 7, 4, c[10], m[14]);
+        perm_elt(a, b, 11, 10, 15, 12, 8, 5, c[9], m[15]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shabal256::{shabal256, shabal256_fast};
+
+    // every lane has to match the scalar hash of its own message
+    fn check_lanes<V: Lanes>() {
+        let lanes = V::LANES;
+        let messages: Vec<Vec<u32>> = (0..lanes)
+            .map(|k| (0..48).map(|i| (i * 0x01010101u32).wrapping_add((k as u32).wrapping_mul(0x9E3779B9)) ^ 0x5A5A5A5A).collect())
+            .collect();
+        let interleave = |word: usize| -> V {
+            let words: Vec<u32> = messages.iter().map(|x| x[word]).collect();
+            unsafe { V::from_slice(&words) }
+        };
+        let data: Vec<V> = (0..32).map(interleave).collect();
+        let term: [V; 16] = std::array::from_fn(|i| interleave(32 + i));
+        let mut dst = [unsafe { V::splat(0) }; 8];
+        unsafe { mshabal256_fast(&data, &term, &mut dst) };
+
+        let mut words = vec![0u32; lanes];
+        for (k, message) in messages.iter().enumerate() {
+            let bytes: Vec<u8> = message[..32].iter().flat_map(|x| x.to_ne_bytes()).collect();
+            let expected = shabal256_fast(&bytes, message[32..].try_into().unwrap());
+            for (i, word) in dst.iter().enumerate() {
+                unsafe { word.write_to(&mut words) };
+                assert_eq!(words[k].to_ne_bytes(), expected[i * 4..i * 4 + 4]);
+            }
+        }
+    }
+
+    fn check_batch<const N: usize>(len: usize) {
+        let messages: Vec<Vec<u8>> = (0..N).map(|k| (0..len).map(|i| (i * 31 + k * 7) as u8).collect()).collect();
+        let messages: [&[u8]; N] = std::array::from_fn(|k| &messages[k][..]);
+        for (message, hash) in messages.iter().zip(shabal256_batch(&messages)) {
+            assert_eq!(hash, shabal256(message), "{} messages of {} bytes", N, len);
+        }
+    }
+
+    #[test]
+    fn batch() {
+        for len in [0, 1, 60, 63, 64, 100, 128, 200] {
+            check_batch::<3>(len);
+            check_batch::<4>(len);
+            check_batch::<8>(len);
+            check_batch::<16>(len);
+        }
+    }
+
+    #[test]
+    fn mshabal256() {
+        check_lanes::<u32>();
+        check_lanes::<Portable<2>>();
+        check_lanes::<Portable<8>>();
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("sse2") {
+                check_lanes::<__m128i>();
+            }
+            if is_x86_feature_detected!("avx2") {
+                check_lanes::<__m256i>();
+            }
+            if is_x86_feature_detected!("avx512f") {
+                check_lanes::<__m512i>();
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use crate::buffer::PageAlignedByteBuffer;
+use std::io::Error;
+use std::path::Path;
+
+/// A NUMA node with the cpus of it this process may run on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NumaNode {
+    pub id: usize,
+    pub cpus: Vec<usize>,
+}
+
+/// Hashing threads of one NUMA node. Each group hashes into its own set of
+/// buffers, placed in the memory of its node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerGroup {
+    pub node: usize,
+    pub cpus: Vec<usize>,
+    pub threads: usize,
+}
+
+/// Parses a kernel cpu list like `0-7,16-23`.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in s.trim().split(',').filter(|x| !x.is_empty()) {
+        let invalid = || format!("invalid cpu list '{}'", s.trim());
+        let cpu = |x: &str| x.trim().parse::<usize>().map_err(|_| invalid());
+        let (first, last) = match part.split_once('-') {
+            Some((a, b)) => (cpu(a)?, cpu(b)?),
+            None => (cpu(part)?, cpu(part)?),
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Formats cpus as a kernel cpu list, the inverse of `parse_cpu_list`.
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &cpu in cpus {
+        match ranges.last_mut() {
+            Some(last) if last.1 + 1 == cpu => last.1 = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|&(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+        .collect();
+    ranges.join(",")
+}
+
+/// The `usable` cpus picked by `list`, all of them without one, less the
+/// `exclude`d ones.
+pub fn select_cpus(usable: &[usize], list: Option<&[usize]>, exclude: &[usize]) -> Vec<usize> {
+    usable
+        .iter()
+        .copied()
+        .filter(|x| list.is_none_or(|list| list.contains(x)) && !exclude.contains(x))
+        .collect()
+}
+
+/// Reads the NUMA nodes below `sys` (normally `/sys`). Nodes without cpus,
+/// e.g. memory only nodes, are left out.
+pub fn read_nodes(sys: &Path) -> Vec<NumaNode> {
+    let entries = match std::fs::read_dir(sys.join("devices/system/node")) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+    let mut nodes: Vec<NumaNode> = entries
+        .filter_map(|x| x.ok())
+        .filter_map(|x| {
+            let id = x.file_name().to_str()?.strip_prefix("node")?.parse().ok()?;
+            let cpus = parse_cpu_list(&std::fs::read_to_string(x.path().join("cpulist")).ok()?).ok()?;
+            Some(NumaNode { id, cpus })
+        })
+        .filter(|x| !x.cpus.is_empty())
+        .collect();
+    nodes.sort_by_key(|x| x.id);
+    nodes
+}
+
+/// The NUMA nodes of the machine restricted to `allowed` cpus. Without NUMA
+/// information all allowed cpus form node 0.
+pub fn topology(sys: &Path, allowed: &[usize]) -> Vec<NumaNode> {
+    let nodes: Vec<NumaNode> = read_nodes(sys)
+        .into_iter()
+        .map(|x| NumaNode {
+            id: x.id,
+            cpus: x.cpus.into_iter().filter(|cpu| allowed.contains(cpu)).collect(),
+        })
+        .filter(|x| !x.cpus.is_empty())
+        .collect();
+    if nodes.is_empty() {
+        return vec![NumaNode {
+            id: 0,
+            cpus: allowed.to_vec(),
+        }];
+    }
+    nodes
+}
+
+/// Spreads `threads` over the nodes in proportion to their cpus. Nodes left
+/// without threads get no group, but there is always at least one group,
+/// which the gpus are driven from.
+pub fn worker_groups(nodes: &[NumaNode], threads: usize) -> Vec<WorkerGroup> {
+    let total: usize = nodes.iter().map(|x| x.cpus.len()).sum::<usize>().max(1);
+    let mut groups: Vec<WorkerGroup> = nodes
+        .iter()
+        .map(|x| WorkerGroup {
+            node: x.id,
+            cpus: x.cpus.clone(),
+            threads: threads * x.cpus.len() / total,
+        })
+        .collect();
+    // the rounding remainder goes to the nodes with the fewest threads per cpu
+    let mut left = threads - groups.iter().map(|x| x.threads).sum::<usize>();
+    while left > 0 {
+        let next = groups
+            .iter_mut()
+            .min_by(|a, b| (a.threads * b.cpus.len()).cmp(&(b.threads * a.cpus.len())))
+            .unwrap();
+        next.threads += 1;
+        left -= 1;
+    }
+    let first = groups.first().cloned();
+    groups.retain(|x| x.threads > 0);
+    if groups.is_empty() {
+        groups.extend(first);
+    }
+    groups
+}
+
+/// Moves the pages of `buffer` to the memory of `group`'s node. Uses
+/// mbind where available, otherwise touches every page from a thread on
+/// the node so that first-touch allocation places it there.
+pub fn place_buffer(buffer: &PageAlignedByteBuffer, group: &WorkerGroup) {
+    let data = buffer.get_buffer();
+    let mut data = data.lock().unwrap();
+    if bind_memory(&mut data, group.node).is_ok() {
+        return;
+    }
+    let cpu = group.cpus[0];
+    let page = page_size::get();
+    let data: &mut [u8] = &mut data;
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            core_affinity::set_for_current(core_affinity::CoreId { id: cpu });
+            for i in (0..data.len()).step_by(page) {
+                data[i] = 0;
+            }
+        });
+    });
+}
+
+// prefers the node's memory for pages not touched yet, falls back to other
+// nodes when it's full
+#[cfg(target_os = "linux")]
+fn bind_memory(data: &mut [u8], node: usize) -> Result<(), Error> {
+    const MPOL_PREFERRED: libc::c_long = 1;
+    const MPOL_MF_MOVE: libc::c_long = 2;
+    let bits = libc::c_ulong::BITS as usize;
+    let mut mask = vec![0 as libc::c_ulong; node / bits + 1];
+    mask[node / bits] |= 1 << (node % bits);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            data.as_mut_ptr(),
+            data.len(),
+            MPOL_PREFERRED,
+            mask.as_ptr(),
+            mask.len() * bits,
+            MPOL_MF_MOVE,
+        )
+    };
+    if result != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_memory(_data: &mut [u8], _node: usize) -> Result<(), Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::ScratchPath;
+    use std::fs;
+
+    fn fake_sys(nodes: &[(&str, &str)]) -> ScratchPath {
+        let sys = ScratchPath::temp(&format!("numa-{}", nodes.len()));
+        fs::create_dir_all(sys.join("devices/system/node")).unwrap();
+        fs::write(sys.join("devices/system/node/possible"), "0-3\n").unwrap();
+        for (name, cpulist) in nodes {
+            let dir = sys.join("devices/system/node").join(name);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("cpulist"), cpulist).unwrap();
+        }
+        sys
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), Ok(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpu_list("\n"), Ok(vec![]));
+        assert_eq!(parse_cpu_list("4,0-1,1"), Ok(vec![0, 1, 4]));
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a-b").is_err());
+        assert_eq!(format_cpu_list(&[0, 1, 2, 3, 8, 10, 11]), "0-3,8,10-11");
+        assert_eq!(format_cpu_list(&[]), "");
+    }
+
+    #[test]
+    fn cpu_selection() {
+        let usable: Vec<usize> = (0..8).collect();
+        assert_eq!(select_cpus(&usable, None, &[]), usable);
+        assert_eq!(select_cpus(&usable, Some(&[2, 3, 4, 12]), &[]), vec![2, 3, 4]);
+        assert_eq!(select_cpus(&usable, None, &[0, 1]), vec![2, 3, 4, 5, 6, 7]);
+        assert_eq!(select_cpus(&usable, Some(&[0, 1]), &[0, 1]), vec![]);
+    }
+
+    #[test]
+    fn numa_topology() {
+        // two sockets with hyperthreads and a memory only node
+        let sys = fake_sys(&[("node1", "4-7,12-15\n"), ("node0", "0-3,8-11\n"), ("node2", "\n")]);
+        let nodes = read_nodes(&sys);
+        assert_eq!(nodes.iter().map(|x| x.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(nodes[1].cpus, vec![4, 5, 6, 7, 12, 13, 14, 15]);
+
+        // a cpuset restricted to node 1
+        let allowed: Vec<usize> = (4..8).collect();
+        let nodes = topology(&sys, &allowed);
+        assert_eq!(nodes, vec![NumaNode { id: 1, cpus: allowed.clone() }]);
+
+        // no NUMA information at all
+        let nodes = topology(Path::new("/nonexistent"), &allowed);
+        assert_eq!(nodes, vec![NumaNode { id: 0, cpus: allowed }]);
+    }
+
+    #[test]
+    fn threads_spread_over_nodes() {
+        let nodes = vec![
+            NumaNode { id: 0, cpus: (0..8).collect() },
+            NumaNode { id: 1, cpus: (8..12).collect() },
+        ];
+        let threads = |n| worker_groups(&nodes, n).iter().map(|x| (x.node, x.threads)).collect::<Vec<_>>();
+        assert_eq!(threads(12), vec![(0, 8), (1, 4)]);
+        assert_eq!(threads(4), vec![(0, 3), (1, 1)]);
+        assert_eq!(threads(1), vec![(0, 1)]);
+        // more threads than cpus, e.g. --cpu 2x cores
+        assert_eq!(threads(24), vec![(0, 16), (1, 8)]);
+        // gpu only plotting keeps one group
+        assert_eq!(threads(0), vec![(0, 0)]);
+        // 300 threads don't fit into a u8
+        assert_eq!(threads(300).iter().map(|x| x.1).sum::<usize>(), 300);
+    }
+}
@@ -1,6 +1,7 @@
 use self::core::{
     ArgVal, ContextProperties, DeviceInfo, Event, KernelWorkGroupInfo, PlatformInfo, Status,
 };
+use crate::cpu_hasher::{SafePointer, SELF_TEST_ID, SELF_TEST_START_NONCE};
 use crate::gpu_hasher::GpuTask;
 use crate::plotter::{NONCE_SIZE, NUM_SCOOPS, SCOOP_SIZE};
 use ocl_core as core;
@@ -572,6 +573,25 @@ pub fn gpu_hash_and_transfer_to_host(
     core::finish(&gpu_context.queue_a).unwrap();
 }
 
+// true if the gpu hashes the self-test nonces like the cpu reference
+pub fn gpu_self_test(gpu_context: &Arc<Mutex<GpuContext>>, reference: &[u8]) -> bool {
+    let mut cache = vec![0u8; reference.len()];
+    let task = GpuTask {
+        cache: SafePointer {
+            ptr: cache.as_mut_ptr(),
+        },
+        cache_size: reference.len() as u64 / NONCE_SIZE,
+        chunk_offset: 0,
+        numeric_id: SELF_TEST_ID,
+        local_startnonce: SELF_TEST_START_NONCE,
+        local_nonces: reference.len() as u64 / NONCE_SIZE,
+    };
+    gpu_hash(gpu_context, &task);
+    // gpu_hash leaves the nonces in buffer a, which is buffer 1 to transfer
+    gpu_transfer_to_host(gpu_context, 1, &task);
+    cache == reference
+}
+
 fn mem_map_gpu_to_host(buffer_id: u8, gpu_context: &GpuContext) -> core::MemMap<u8> {
     unsafe {
         if buffer_id == 1 {
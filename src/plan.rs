@@ -0,0 +1,160 @@
+use crate::cpu_hasher::SimdExtension;
+use crate::device::PlotDevice;
+use crate::numa::WorkerGroup;
+use crate::plotter::NONCE_SIZE;
+use std::path::PathBuf;
+
+/// Outcome of the pre-flight checks of a plotting task. Produced by
+/// `Plotter::plan` and reported as is by `--dry-run`.
+pub struct PlotPlan {
+    pub file: PathBuf,
+    pub nonces: u64,
+    pub sector_size: u64,
+    pub plotsize: u64,
+    pub free_disk_space: u64,
+    pub fits_on_disk: bool,
+    pub file_exists: bool,
+    pub progress: u64,
+    pub mem: u64,
+    pub gpu_mem_needed: u64,
+    pub num_buffer: u64,
+    pub buffer_size: u64,
+    pub cpu_threads: usize,
+    pub simd_ext: SimdExtension,
+    // set when plotting onto a device, slot is the index entry of an existing plot
+    pub device: Option<PlotDevice>,
+    pub device_slot: Option<usize>,
+    // hashing threads per numa node, each group with buffers of its own
+    pub groups: Vec<WorkerGroup>,
+    // cpus of the writer and scheduler threads, None when they're not pinned
+    pub io_cpus: Option<Vec<usize>>,
+}
+
+impl PlotPlan {
+    // byte offset of the plot on the plot device
+    fn device_offset(&self) -> Option<u64> {
+        self.device.as_ref().map(|x| match self.device_slot {
+            Some(slot) => x.entries[slot].offset,
+            None => x.next_offset(),
+        })
+    }
+
+    // prints the plan, either human readable or as a single line json object.
+    // The estimate is based on the cpu hashing rate, None when gpus plot.
+    pub fn report(&self, json: bool, nonces_per_minute: Option<f64>) {
+        let eta_seconds = match nonces_per_minute {
+            Some(x) if x > 0.0 => Some(((self.nonces - self.progress) as f64 / x * 60.0) as u64),
+            _ => None,
+        };
+
+        if json {
+            println!("{}", self.to_json(nonces_per_minute, eta_seconds));
+            return;
+        }
+
+        println!("Plan:");
+        match self.device_offset() {
+            Some(offset) => println!("  Device:        {} (offset {})", self.file.display(), offset),
+            None => println!("  File:          {}", self.file.display()),
+        }
+        println!("  Nonces:        {}", self.nonces);
+        if self.sector_size > 0 {
+            println!("  Sector size:   {} B", self.sector_size);
+        }
+        println!(
+            "  Plot size:     {:.2} GiB, free={:.2} GiB{}",
+            gib(self.plotsize),
+            gib(self.free_disk_space),
+            if self.fits_on_disk { "" } else { " (INSUFFICIENT)" }
+        );
+        if self.file_exists {
+            println!("  Resume from:   nonce offset {}", self.progress);
+        }
+        println!(
+            "  Buffers:       {} x {:.2} GiB (HDDcache={:.2} GiB, GPUcache={:.2} GiB)",
+            self.num_buffer,
+            gib(self.buffer_size),
+            gib(self.mem),
+            gib(self.gpu_mem_needed)
+        );
+        println!(
+            "  CPU threads:   {} [{:?}]",
+            self.cpu_threads, self.simd_ext
+        );
+        if self.groups.len() > 1 {
+            let groups: Vec<String> = self
+                .groups
+                .iter()
+                .map(|x| format!("node{}: {}", x.node, x.threads))
+                .collect();
+            println!("  NUMA nodes:    {} ({})", self.groups.len(), groups.join(", "));
+        }
+        match (nonces_per_minute, eta_seconds) {
+            (Some(rate), Some(eta)) => println!(
+                "  Estimate:      {:.0} nonces/m (cpu), {}h{:02}m{:02}s",
+                rate,
+                eta / 3600,
+                eta / 60 % 60,
+                eta % 60
+            ),
+            (None, _) => println!("  Estimate:      n/a (gpus aren't benchmarked)"),
+            _ => println!("  Estimate:      n/a (no cpu threads to benchmark)"),
+        }
+    }
+
+    fn to_json(&self, nonces_per_minute: Option<f64>, eta_seconds: Option<u64>) -> String {
+        format!(
+            "{{\"file\":\"{}\",\"nonces\":{},\"sector_size\":{},\"plot_size\":{},\
+             \"free_disk_space\":{},\"fits_on_disk\":{},\"file_exists\":{},\
+             \"resume_nonce_offset\":{},\"hdd_cache\":{},\"gpu_cache\":{},\
+             \"buffers\":{},\"buffer_size\":{},\"buffer_nonces\":{},\"cpu_threads\":{},\
+             \"simd\":\"{:?}\",\"nonces_per_minute\":{},\"estimated_seconds\":{},\
+             \"device_offset\":{},\"numa_nodes\":{}}}",
+            json_escape(&self.file.display().to_string()),
+            self.nonces,
+            self.sector_size,
+            self.plotsize,
+            self.free_disk_space,
+            self.fits_on_disk,
+            self.file_exists,
+            self.progress,
+            self.mem,
+            self.gpu_mem_needed,
+            self.num_buffer,
+            self.buffer_size,
+            self.buffer_size / NONCE_SIZE,
+            self.cpu_threads,
+            self.simd_ext,
+            match nonces_per_minute {
+                Some(x) => format!("{:.0}", x),
+                None => "null".to_string(),
+            },
+            match eta_seconds {
+                Some(x) => x.to_string(),
+                None => "null".to_string(),
+            },
+            match self.device_offset() {
+                Some(x) => x.to_string(),
+                None => "null".to_string(),
+            },
+            self.groups.len()
+        )
+    }
+}
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / 1024.0 / 1024.0 / 1024.0
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -1,45 +1,143 @@
 use humanize_rs::bytes::Bytes;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use raw_cpuid::CpuId;
 
-use crate::cpu_hasher::{SimdExtension,init_simd};
-use crate::buffer::PageAlignedByteBuffer;
+use crate::cpu_hasher::{
+    available_simd, bench_cpu, bench_cpu_pages, init_simd, self_test_cpu, self_test_reference, SimdExtension,
+};
+use crate::buffer::{HugePages, PageAlignedByteBuffer};
+use crate::cgroup::CgroupLimits;
+use crate::device::PlotDevice;
+use crate::plan::PlotPlan;
 #[cfg(feature = "opencl")]
-use crate::ocl::gpu_get_info;
-use crate::scheduler::create_scheduler_thread;
+use crate::ocl::{gpu_get_info, gpu_init, gpu_self_test};
+use crate::numa::{format_cpu_list, place_buffer, select_cpus, topology, worker_groups};
+use crate::scheduler::{create_scheduler_thread, NonceCursor, Workers};
 #[cfg(windows)]
 use crate::utils::set_thread_ideal_processor;
-use crate::utils::{free_disk_space, get_sector_size, preallocate};
-use crate::writer::{create_writer_thread, read_resume_info, write_resume_info};
+use crate::utils::{free_disk_space, get_sector_size, preallocate, raise_memlock_limit, set_thread_affinity, Prealloc};
+use crate::writer::{
+    bench_writers, create_writer_thread, read_resume_info, write_resume_info, MmapAdvice, PlotTarget,
+    WriteAbort,
+};
 use core_affinity;
 use crossbeam_channel::bounded;
 use std::cmp::{max, min};
+use std::fmt;
+use std::io::Error;
 use std::path::Path;
 use std::process;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::thread;
+use std::time::Duration;
 use stopwatch::Stopwatch;
 
 pub const SCOOP_SIZE: u64 = 64;
 pub const NUM_SCOOPS: u64 = 4096;
 pub const NONCE_SIZE: u64 = SCOOP_SIZE * NUM_SCOOPS;
 
+// cpuid only exists on x86
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpu_name() -> String {
+    let cpuid = CpuId::new();
+    cpuid
+        .get_processor_brand_string()
+        .map(|s| s.as_str().trim().to_string())
+        .unwrap_or_else(|| {
+            cpuid
+                .get_vendor_info()
+                .map(|v| v.as_str().to_string())
+                .unwrap_or_else(|| "Unknown CPU".to_string())
+        })
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn cpu_name() -> String {
+    "Unknown CPU".to_string()
+}
+
 pub struct Plotter {}
 
+/// Why a plot was given up on.
+#[derive(Debug)]
+pub enum PlotError {
+    // the writer stopped, the plot can be resumed
+    Write(WriteAbort),
+    // the new plot file couldn't be set up, it was removed again
+    Prealloc(Prealloc, Error),
+}
+
+impl fmt::Display for PlotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlotError::Write(x) => x.fmt(f),
+            PlotError::Prealloc(strategy, e) => write!(
+                f,
+                "couldn't preallocate space for file ({}): {}. Try another strategy with --prealloc.",
+                strategy, e
+            ),
+        }
+    }
+}
+
+impl From<WriteAbort> for PlotError {
+    fn from(x: WriteAbort) -> PlotError {
+        PlotError::Write(x)
+    }
+}
+
 pub struct PlotterTask {
     pub numeric_id: u64,
     pub start_nonce: u64,
     pub nonces: u64,
     pub output_path: String,
+    // plot onto this block device (or partition) instead of into output_path
+    pub device: Option<String>,
     pub mem: String,
-    pub cpu_threads: u8,
+    pub cpu_threads: usize,
     pub gpus: Option<Vec<String>>,
     pub direct_io: bool,
-    pub async_io: bool,
+    // overrides the detected sector size of the target
+    pub sector_size: Option<u64>,
+    pub buffers: u64,
     pub quiet: bool,
     pub benchmark: bool,
     pub zcb: bool,
+    pub dry_run: bool,
+    pub io_uring: bool,
+    pub queue_depth: u32,
+    // scoop regions re-read per written buffer, 0 disables write verification
+    pub verify_scoops: u64,
+    // buffers between flushes of data and resume marker, 0 never flushes
+    pub sync_interval: u64,
+    // bytes per second written to the target disk, 0 is unlimited
+    pub max_write_rate: u64,
+    // file holding the write rate limit, re-read while plotting
+    pub write_rate_file: Option<String>,
+    pub prealloc: Prealloc,
+    // write through a memory mapping of the plot file instead of write calls
+    pub mmap: Option<MmapAdvice>,
+    // repetitions of a failed write before plotting is aborted
+    pub write_retries: u32,
+    // wait before the first repetition, doubled for each one after
+    pub retry_delay: Duration,
+    // page size backing the plotting buffers
+    pub huge_pages: HugePages,
+    // lock the plotting buffers in RAM
+    pub mlock: bool,
+    // cpus for hashing threads, for writer and scheduler threads, and cpus
+    // to leave alone. Without lists all usable cpus are taken.
+    pub cpu_list: Option<Vec<usize>>,
+    pub io_cpu_list: Option<Vec<usize>>,
+    pub exclude_cpus: Vec<usize>,
+    // backend forced with --simd, None picks the widest the cpu has
+    pub simd: Option<SimdExtension>,
+    // benchmark mode also hashes with every available backend
+    pub all_simd: bool,
+    // hash a few nonces with the backends in use before plotting
+    pub self_test: bool,
 }
 
 impl Plotter {
@@ -47,22 +145,35 @@ impl Plotter {
         Plotter {}
     }
 
-    pub fn run(&self, mut task: PlotterTask) {
-        let cpuid = CpuId::new();
-        let cpu_name = cpuid
-            .get_processor_brand_string()
-            .map(|s| s.as_str().trim().to_string())
-            .unwrap_or_else(|| {
-                cpuid
-                    .get_vendor_info()
-                    .map(|v| v.as_str().to_string())
-                    .unwrap_or_else(|| "Unknown CPU".to_string())
-            });
-        
-        let cores = sys_info::cpu_num().unwrap();
-        let memory = sys_info::mem_info().unwrap();
-
-        let simd_ext = init_simd();
+    /// Runs all pre-flight checks for a task (sector size, nonce rounding, free
+    /// space, memory split, resume info) without touching the disk or
+    /// allocating plotting buffers. Returns `None` if the task can't be plotted.
+    /// `reserved_space` is disk space promised to the earlier files of a
+    /// `--sna` dry run, which don't exist yet to take it.
+    pub fn plan(&self, task: &mut PlotterTask, reserved_space: u64) -> Option<PlotPlan> {
+        let cpu_name = cpu_name();
+
+        // containers see the host's cpus and memory, their cgroups limit them
+        let limits = CgroupLimits::read(Path::new("/"));
+        let usable = limits.usable_cpus();
+        let allowed = select_cpus(&usable, task.cpu_list.as_deref(), &task.exclude_cpus);
+        let cores = limits.cpu_count(&allowed);
+        // writer and scheduler threads are only pinned when asked for
+        let io_cpus = if task.io_cpu_list.is_some() || !task.exclude_cpus.is_empty() {
+            Some(select_cpus(&usable, task.io_cpu_list.as_deref(), &task.exclude_cpus)).filter(|x| !x.is_empty())
+        } else {
+            None
+        };
+        let mut memory = sys_info::mem_info().unwrap();
+        if let Some(limit) = limits.memory {
+            memory.total = min(memory.total, limit / 1024);
+        }
+        if let Some(avail) = limits.memory_avail {
+            memory.avail = min(memory.avail, avail / 1024);
+            memory.free = min(memory.free, avail / 1024);
+        }
+
+        let simd_ext = task.simd.clone().unwrap_or_else(init_simd);
 
         if !task.quiet {
            println!("anne-plotter {}\n", env!("CARGO_PKG_VERSION"));
@@ -72,15 +183,38 @@ impl Plotter {
             println!("*BENCHMARK MODE*\n");
         }
 
+        if !task.quiet && task.dry_run {
+            println!("*DRY RUN*\n");
+        }
+
         if !task.quiet {
             println!(
-                "CPU: {} [using {} of {} cores{}{:?}]",
+                "CPU: {} [using {} of {} cores{}]",
                 cpu_name,
                 task.cpu_threads,
                 cores,
-                if let SimdExtension::None = &simd_ext { "" } else { " + " },
-                &simd_ext
+                match &simd_ext {
+                    SimdExtension::None => String::new(),
+                    x => format!(" + {:?}", x),
+                }
             );
+            let mut cgroup = Vec::new();
+            if let Some(x) = limits.memory {
+                cgroup.push(format!("memory={:.2} GiB", x as f64 / 1024.0 / 1024.0 / 1024.0));
+            }
+            if let Some(x) = limits.cpu_quota {
+                cgroup.push(format!("cpu quota={:.2}", x));
+            }
+            if !cgroup.is_empty() {
+                println!("cgroup limits: {}", cgroup.join(", "));
+            }
+            if task.cpu_list.is_some() || io_cpus.is_some() {
+                println!(
+                    "CPUs: hashing={}, writer={}",
+                    format_cpu_list(&allowed),
+                    io_cpus.as_deref().map_or("any".to_string(), format_cpu_list)
+                );
+            }
         }
 
         #[cfg(not(feature = "opencl"))]
@@ -98,55 +232,114 @@ impl Plotter {
             gpu_mem_needed / 2
         };
 
-        let free_disk_space = free_disk_space(&task.output_path);
+        let device = match &task.device {
+            Some(path) => match PlotDevice::open(Path::new(path)) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    println!("Error: couldn't open device '{}': {}", path, e);
+                    println!("Shutting down...");
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        let free_disk_space = match &device {
+            Some(x) => x.free_space(),
+            None => free_disk_space(&task.output_path),
+        }
+        .saturating_sub(reserved_space);
         if task.nonces == 0 {
             task.nonces = free_disk_space / NONCE_SIZE;
         }
 
         let gpu = task.gpus.is_some();
 
-        let mut rounded_nonces_to_sector_size = false;
+        // direct i/o writes whole sectors, unaligned scoop regions are
+        // read-modify-written by the writer. Write verification reads with
+        // direct i/o as well.
         let mut nonces_per_sector = 1;
+        let mut sector_size = 0;
+        if task.direct_io || task.verify_scoops > 0 {
+            let target = match &task.device {
+                Some(x) => x,
+                None => &task.output_path,
+            };
+            sector_size = match task.sector_size.map(|x| Ok((x, false))).unwrap_or_else(|| get_sector_size(target)) {
+                Ok((x, guessed)) => {
+                    if guessed && !task.quiet {
+                        println!(
+                            "Warning: no block device behind {} (network, memory or pooled file system), assuming {} B sectors from its block size. Use --sector-size to set it.",
+                            target, x
+                        );
+                    }
+                    x
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    println!("Please specify it with --sector-size or disable direct i/o with --ddio.");
+                    println!("Shutting down...");
+                    return None;
+                }
+            };
+        }
         if task.direct_io {
-            let sector_size = get_sector_size(&task.output_path);
             nonces_per_sector = sector_size / SCOOP_SIZE;
-            if task.nonces % nonces_per_sector > 0 {
-                rounded_nonces_to_sector_size = true;
-                task.nonces /= nonces_per_sector;
-                task.nonces *= nonces_per_sector;
-            }
         }
 
         let plotsize = task.nonces * NONCE_SIZE;
 
-        let file = Path::new(&task.output_path).join(format!(
-            "{}_{}_{}",
-            task.numeric_id, task.start_nonce, task.nonces
-        ));
+        let file = match &device {
+            Some(x) => x.path.clone(),
+            None => Path::new(&task.output_path).join(format!(
+                "{}_{}_{}",
+                task.numeric_id, task.start_nonce, task.nonces
+            )),
+        };
+        let device_slot = device
+            .as_ref()
+            .and_then(|x| x.find(task.numeric_id, task.start_nonce, task.nonces));
 
-        if !file.parent().unwrap().exists() {
+        if device.is_none() && !file.parent().unwrap().exists() {
             println!(
                 "Error: specified target path does not exist, path={}",
                 &task.output_path
             );
             println!("Shutting down...");
-            return;
+            return None;
         }
 
-        if free_disk_space < plotsize && !file.exists() && !task.benchmark {
+        let file_exists = match &device {
+            Some(_) => device_slot.is_some(),
+            None => file.exists(),
+        };
+        // a full disk leaves no nonces to fill it with
+        let fits_on_disk = (plotsize > 0 && free_disk_space >= plotsize) || file_exists;
+        if !fits_on_disk && !task.benchmark && !task.dry_run {
             println!(
                 "Error: insufficient disk space, MiB_required={:.2}, MiB_available={:.2}",
                 plotsize as f64 / 1024.0 / 1024.0,
                 free_disk_space as f64 / 1024.0 / 1024.0
             );
             println!("Shutting down...");
-            return;
+            return None;
         }
 
-        let mem = match calculate_mem_to_use(&task, &memory, nonces_per_sector, gpu, gpu_mem_needed)
+        // one set of buffers per worker group, in the memory of its numa node
+        let groups = worker_groups(&topology(Path::new("/sys"), &allowed), task.cpu_threads);
+        let num_buffer = task.buffers * groups.len() as u64;
+
+        // as far as permitted, failures show when the buffers are locked
+        let memlock = if task.mlock {
+            raise_memlock_limit().ok().flatten()
+        } else {
+            None
+        };
+
+        let mem = match calculate_mem_to_use(task, &memory, nonces_per_sector, num_buffer, memlock, gpu, gpu_mem_needed)
         {
             Ok(x) => x,
-            Err(_) => return,
+            Err(_) => return None,
         };
 
         if !task.quiet {
@@ -166,22 +359,31 @@ impl Plotter {
 
             println!("Numeric ID:  {}", task.numeric_id);
             println!("Start Nonce: {}", task.start_nonce);
-            println!(
-                "Nonces:      {}{}",
-                task.nonces,
-                if rounded_nonces_to_sector_size {
-                    &" (rounded to sector size for fast direct i/o)"
-                } else {
-                    &""
-                }
-            );
+            println!("Nonces:      {}", task.nonces);
         }
 
         if !task.quiet {
-            println!("Output File: {}\n", file.display());
+            match &device {
+                Some(x) => println!(
+                    "Output Device: {} (offset {}{})\n",
+                    file.display(),
+                    match device_slot {
+                        Some(slot) => x.entries[slot].offset,
+                        None => x.next_offset(),
+                    },
+                    if x.formatted { "" } else { ", creating plot index" }
+                ),
+                None => println!("Output File: {}\n", file.display()),
+            }
         }
         let mut progress = 0;
-        if file.exists() {
+        if let (Some(device), Some(slot)) = (&device, device_slot) {
+            progress = device.entries[slot].progress;
+            if progress == task.nonces {
+                println!("Plot is already complete on device '{}'", file.display());
+                return None;
+            }
+        } else if file_exists {
             if !task.quiet {
                 println!("File already exists, reading resume info...");
             }
@@ -193,24 +395,97 @@ impl Plotter {
                     println!("If you are sure that this file is incomplete \
                               or corrupted, then delete it before continuing.");
                     println!("Shutting Down...");
-                    return;
+                    return None;
                 }
             }
             if !task.quiet {
                 println!("OK");
             }
-        } else {
+        }
+
+        Some(PlotPlan {
+            file,
+            nonces: task.nonces,
+            sector_size,
+            plotsize,
+            free_disk_space,
+            fits_on_disk,
+            file_exists,
+            progress,
+            mem,
+            gpu_mem_needed,
+            num_buffer,
+            buffer_size: mem / num_buffer,
+            cpu_threads: task.cpu_threads,
+            simd_ext,
+            device,
+            device_slot,
+            groups,
+            io_cpus,
+        })
+    }
+
+    /// Plots a task. Returns an error if the plot file couldn't be set up or
+    /// the writer gave up on it, other failures are reported on the console
+    /// only.
+    pub fn run(&self, mut task: PlotterTask) -> Result<(), PlotError> {
+        let mut plan = match self.plan(&mut task, 0) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let file = plan.file.clone();
+        let plotsize = plan.plotsize;
+        let progress = plan.progress;
+        let simd_ext = plan.simd_ext.clone();
+
+        // a miscompiled or misdetected backend must not write a single plot,
+        // the backends are the same for every file of a run
+        static SELF_TEST: OnceLock<bool> = OnceLock::new();
+        if task.self_test {
+            let cpu = if task.cpu_threads > 0 { Some(&simd_ext) } else { None };
+            if !*SELF_TEST.get_or_init(|| self_test(cpu, task.gpus.as_deref(), task.zcb, task.quiet)) {
+                eprintln!("Refusing to plot. Pick another backend with --simd, or skip the test with --no-self-test.");
+                process::exit(1);
+            }
+        }
+
+        let target = match plan.device.take() {
+            Some(mut device) if !task.benchmark => {
+                let slot = match plan.device_slot {
+                    Some(x) => x,
+                    None => match device.add_plot(task.numeric_id, task.start_nonce, task.nonces) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            println!("Error: couldn't add plot to device index: {}", e);
+                            println!("Shutting down...");
+                            return Ok(());
+                        }
+                    },
+                };
+                PlotTarget::Device(device, slot)
+            }
+            _ => PlotTarget::File(file.clone()),
+        };
+
+        if !plan.file_exists && task.device.is_none() {
             if !task.quiet {
-                print!("Fast file pre-allocation...");
+                print!("File pre-allocation...");
             }
+            let mut strategy = task.prealloc;
             if !task.benchmark {
-                preallocate(&file, plotsize, task.direct_io);
-                if write_resume_info(&file, 0u64).is_err() {
-                    println!("Error: couldn't write resume info");
-                }
+                let prealloc = preallocate(&file, plotsize, task.direct_io, task.prealloc)
+                    .and_then(|x| write_resume_info(&file, 0u64).map(|_| x));
+                strategy = match prealloc {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // a file without resume marker would stop the next run
+                        let _ = std::fs::remove_file(&file);
+                        return Err(PlotError::Prealloc(task.prealloc, e));
+                    }
+                };
             }
             if !task.quiet {
-                println!("OK");
+                println!("OK ({})", strategy);
             }
         }
 
@@ -222,14 +497,42 @@ impl Plotter {
             }
         }
 
-        let num_buffer = if task.async_io { 2 } else { 1 };
-        let buffer_size = mem / num_buffer;
-        let (tx_empty_buffers, rx_empty_buffers) = bounded(num_buffer as usize);
+        let num_buffer = plan.num_buffer;
+        let buffer_size = plan.buffer_size;
+        let groups = plan.groups.clone();
+        let io_cpus = plan.io_cpus.clone();
         let (tx_full_buffers, rx_full_buffers) = bounded(num_buffer as usize);
 
-        for _ in 0..num_buffer {
-            let buffer = PageAlignedByteBuffer::new(buffer_size as usize);
-            tx_empty_buffers.send(buffer).unwrap();
+        // every worker group hashes into buffers of its own
+        let mut pages = task.huge_pages;
+        let mut lock_failed = false;
+        let mut tx_empty_buffers = Vec::new();
+        let mut rx_empty_buffers = Vec::new();
+        for (i, group) in groups.iter().enumerate() {
+            let group_buffers = num_buffer / groups.len() as u64;
+            let (tx, rx) = bounded(group_buffers as usize);
+            for _ in 0..group_buffers {
+                let mut buffer = PageAlignedByteBuffer::with_huge_pages(buffer_size as usize, task.huge_pages);
+                buffer.group = i;
+                if groups.len() > 1 {
+                    place_buffer(&buffer, group);
+                }
+                if task.mlock
+                    && !lock_failed
+                    && let Err(e) = buffer.lock()
+                {
+                    println!("Warning: couldn't lock buffers in RAM: {}. They may be swapped out.", e);
+                    println!("Raise the limit with 'ulimit -l' or grant CAP_IPC_LOCK to lock them.\n");
+                    lock_failed = true;
+                }
+                pages = min(pages, buffer.pages);
+                tx.send(buffer).unwrap();
+            }
+            tx_empty_buffers.push(tx);
+            rx_empty_buffers.push(rx);
+        }
+        if !task.quiet && pages != task.huge_pages {
+            println!("Warning: {} huge pages unavailable, buffers use {} pages.\n", task.huge_pages, pages);
         }
 
         let mb = MultiProgress::new();
@@ -250,7 +553,7 @@ impl Plotter {
         let p2x = if !task.quiet {
         let pb = mb.add(ProgressBar::new(plotsize - progress * NONCE_SIZE));
         pb.set_style(ProgressStyle::default_bar()
-            .template("{prefix:>12} {wide_bar} {bytes:>8} {bytes_per_sec:>10}")
+            .template("{prefix:>12} {wide_bar} {bytes:>8} {bytes_per_sec:>10} {msg}")
             .expect("Failed to set template")
             .progress_chars("██░"));
         pb.set_prefix("Writing:");
@@ -264,54 +567,91 @@ impl Plotter {
         let task = Arc::new(task);
 
         let thread_pinning = true;
-        let core_ids = if thread_pinning {
-            core_affinity::get_core_ids().unwrap()
-        } else {
-            Vec::new()
-        };
-
-        let hasher = thread::spawn({
-            create_scheduler_thread(
-                task.clone(),
-                rayon::ThreadPoolBuilder::new()
-                    .num_threads(task.cpu_threads as usize)
+        let cursor = Arc::new(NonceCursor::new(progress, task.nonces));
+
+        // one scheduler per worker group, its threads pinned to the cpus of
+        // its node, the first one drives the gpus as well
+        let hashers: Vec<_> = groups
+            .iter()
+            .zip(rx_empty_buffers)
+            .enumerate()
+            .map(|(i, (group, rx_empty_buffers))| {
+                let cpus = group.cpus.clone();
+                // rayon takes 0 threads for one per cpu. The pool of a gpu-only
+                // group stays unused, a single thread will do.
+                let thread_pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(max(group.threads, 1))
                     .start_handler(move |id| {
                         if thread_pinning {
                             #[cfg(not(windows))]
-                            let core_id = core_ids[id % core_ids.len()];
-                            #[cfg(not(windows))]
-                            core_affinity::set_for_current(core_id);
+                            core_affinity::set_for_current(core_affinity::CoreId {
+                                id: cpus[id % cpus.len()],
+                            });
                             #[cfg(windows)]
-                            set_thread_ideal_processor(id % core_ids.len());
+                            set_thread_ideal_processor(cpus[id % cpus.len()]);
                         }
                     })
                     .build()
-                    .unwrap(),
-                progress,
-                p1x,
-                rx_empty_buffers.clone(),
-                tx_full_buffers.clone(),
-                simd_ext,
-            )
-        });
+                    .unwrap();
+                let scheduler = create_scheduler_thread(
+                    task.clone(),
+                    Workers {
+                        thread_pool,
+                        cpu_threads: group.threads,
+                        gpus: i == 0,
+                    },
+                    cursor.clone(),
+                    p1x.clone(),
+                    rx_empty_buffers,
+                    tx_full_buffers.clone(),
+                    simd_ext.clone(),
+                );
+                let io_cpus = io_cpus.clone();
+                thread::spawn(move || {
+                    pin_io_thread(io_cpus.as_deref());
+                    scheduler()
+                })
+            })
+            .collect();
+        drop(tx_full_buffers);
 
         let writer = thread::spawn({
-            create_writer_thread(
+            let writer = create_writer_thread(
                 task.clone(),
+                target,
                 progress,
+                plan.sector_size,
                 p2x,
-                rx_full_buffers.clone(),
-                tx_empty_buffers.clone(),
-            )
+                rx_full_buffers,
+                tx_empty_buffers,
+            );
+            move || {
+                pin_io_thread(io_cpus.as_deref());
+                writer()
+            }
         });
 
-        writer.join().unwrap();
-        hasher.join().unwrap();
+        // the channels are owned by the two threads alone, so the hasher
+        // stops once an aborted writer drops its ends
+        let writer_result = writer.join().unwrap();
+        // the cpu pools of all groups count as one worker
+        let mut hasher_stall = Duration::ZERO;
+        let mut hashed: Vec<(String, u64)> = Vec::new();
+        for stats in hashers.into_iter().map(|x| x.join().unwrap()) {
+            hasher_stall = hasher_stall.max(stats.stall);
+            for (name, nonces) in stats.hashed {
+                match hashed.iter_mut().find(|x| x.0 == name) {
+                    Some(x) => x.1 += nonces,
+                    None => hashed.push((name, nonces)),
+                }
+            }
+        }
 
         if !task.quiet {
 
             let _ = mb.clear();
         }
+        let writer_stall = writer_result?;
 
         let elapsed = sw.elapsed_ms() as u64;
         let hours = elapsed / 1000 / 60 / 60;
@@ -328,7 +668,92 @@ impl Plotter {
                 (task.nonces - progress) as f64 * 1000.0 / (elapsed as f64 + 1.0) / 4.0,
                 (task.nonces - progress) as f64 * 1000.0 / (elapsed as f64 + 1.0) * 60.0
             );
+            println!(
+                "Buffers: {}, hasher waited {:.1}s for empty buffers, writer waited {:.1}s for full buffers.",
+                num_buffer,
+                hasher_stall.as_secs_f64(),
+                writer_stall.as_secs_f64()
+            );
+            if hashed.len() > 1 {
+                let rates: Vec<String> = hashed
+                    .iter()
+                    .map(|(name, nonces)| {
+                        format!("{} {:.0}", name, *nonces as f64 * 1000.0 / (elapsed as f64 + 1.0) * 60.0)
+                    })
+                    .collect();
+                println!("Workers (nonces/m): {}.", rates.join(", "));
+            }
         }
+
+        if task.benchmark && !task.quiet {
+            println!("\nHashing benchmark:");
+            for pages in [HugePages::Off, HugePages::Transparent, HugePages::Huge2M, HugePages::Huge1G] {
+                match bench_cpu_pages(task.cpu_threads, &plan.simd_ext, pages) {
+                    Some(x) => println!("  pages {:<4}{:>12.0} nonces/m", pages, x),
+                    None => println!("  pages {:<4}{:>12}", pages, "unavailable"),
+                }
+            }
+        }
+
+        // the same workload on every backend the cpu has
+        if task.benchmark && task.all_simd && !task.quiet {
+            println!("\nSIMD benchmark:");
+            for simd_ext in available_simd() {
+                println!("  {:<10}{:>12.0} nonces/m", simd_ext, bench_cpu(task.cpu_threads, &simd_ext));
+            }
+        }
+
+        // benchmark mode writes nothing while plotting, the writer modes
+        // are measured on their own with a scratch plot
+        if task.benchmark && !task.quiet && task.device.is_none() {
+            println!("\nWriter benchmark:");
+            for (mode, result) in bench_writers(Path::new(&task.output_path), plan.sector_size) {
+                match result {
+                    Ok(x) => println!("  {:<10}{:>10.2} MiB/s", mode, x),
+                    Err(e) => println!("  {:<10}{:>10}", mode, e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hashes a few nonces with the cpu backend (None if the cpu doesn't hash)
+/// and each gpu, and compares them with the scalar reference. A broken
+/// backend would otherwise fill whole disks with bad plots. False on any
+/// mismatch.
+fn self_test(simd_ext: Option<&SimdExtension>, gpus: Option<&[String]>, zcb: bool, quiet: bool) -> bool {
+    let reference = self_test_reference();
+    let mut ok = true;
+    let mut report = |name: String, passed: bool| {
+        if !passed {
+            eprintln!("Error: self-test of {} failed, it hashes differently than the scalar reference.", name);
+            ok = false;
+        } else if !quiet {
+            println!("Self-test: {} OK", name);
+        }
+    };
+    if let Some(simd_ext) = simd_ext {
+        report(format!("the {} backend", simd_ext), self_test_cpu(simd_ext, &reference));
+    }
+    #[cfg(feature = "opencl")]
+    if let Some(gpus) = gpus {
+        for (gpu, context) in gpus.iter().zip(gpu_init(gpus, zcb)) {
+            report(format!("gpu {}", gpu), gpu_self_test(&context, &reference));
+        }
+    }
+    #[cfg(not(feature = "opencl"))]
+    let _ = (gpus, zcb);
+    ok
+}
+
+// keeps writer and scheduler threads on the cpus given for them, off the
+// hashing cpus if need be
+fn pin_io_thread(cpus: Option<&[usize]>) {
+    if let Some(cpus) = cpus
+        && let Err(e) = set_thread_affinity(cpus)
+    {
+        println!("Warning: couldn't pin thread to cpus {}: {}", format_cpu_list(cpus), e);
     }
 }
 
@@ -336,6 +761,8 @@ fn calculate_mem_to_use(
     task: &PlotterTask,
     memory: &sys_info::MemInfo,
     nonces_per_sector: u64,
+    num_buffer: u64,
+    memlock: Option<u64>,
     gpu: bool,
     gpu_mem_needed: u64,
 ) -> Result<u64, &'static str> {
@@ -362,7 +789,7 @@ fn calculate_mem_to_use(
     }
 
     if gpu && mem > 0 {
-        mem -= gpu_mem_needed;
+        mem = mem.saturating_sub(gpu_mem_needed);
     }
 
     if mem == 0 {
@@ -376,13 +803,39 @@ fn calculate_mem_to_use(
         nonces_per_sector
     };
 
-    mem = min(mem, get_avail_mem(&memory) * 1000 - gpu_mem_needed);
+    // a container's memory may be all but used up, allocating past its
+    // cgroup limit gets the plotter killed
+    let min_mem = num_buffer * NONCE_SIZE * nonces_per_sector;
+    let avail_mem = (get_avail_mem(memory) * 1000).saturating_sub(gpu_mem_needed);
+    if avail_mem < min_mem {
+        println!(
+            "Error: insufficient memory, MiB_required={:.2}, MiB_available={:.2}",
+            (min_mem + gpu_mem_needed) as f64 / 1024.0 / 1024.0,
+            (get_avail_mem(memory) * 1000) as f64 / 1024.0 / 1024.0
+        );
+        println!("Shutting down...");
+        return Err("insufficient memory");
+    }
+    mem = min(mem, avail_mem);
+
+    // locked buffers can't exceed RLIMIT_MEMLOCK
+    if let Some(limit) = memlock
+        && mem > limit
+    {
+        if !task.quiet {
+            println!(
+                "Warning: only {:.2} MiB can be locked in RAM (RLIMIT_MEMLOCK), buffers are reduced to that.",
+                limit as f64 / 1024.0 / 1024.0
+            );
+            println!("Raise the limit with 'ulimit -l' or in /etc/security/limits.conf to use more memory.\n");
+        }
+        mem = limit;
+    }
 
-    let num_buffer = if task.async_io { 2 } else { 1 };
-    mem /= num_buffer * NONCE_SIZE * nonces_per_sector;
-    mem *= num_buffer * NONCE_SIZE * nonces_per_sector;
+    mem /= min_mem;
+    mem *= min_mem;
 
-    mem = max(mem, num_buffer * NONCE_SIZE * nonces_per_sector);
+    mem = max(mem, min_mem);
     Ok(mem)
 }
 
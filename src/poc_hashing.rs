@@ -1,4 +1,11 @@
-use crate::shabal256::shabal256_fast;
+use anne_plotter::mshabal::{mshabal256_fast, Lanes, Portable};
+use anne_plotter::shabal256::shabal256_fast;
+use std::cell::RefCell;
+use std::mem::size_of;
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
 
 const HASH_SIZE: usize = 32;
 const HASH_CAP: usize = 4096;
@@ -7,24 +14,63 @@ const SCOOP_SIZE: usize = 64;
 const NONCE_SIZE: usize = NUM_SCOOPS * SCOOP_SIZE;
 const MESSAGE_SIZE: usize = 16;
 
+// a piece of work buffer, aligned for every lane type
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct Block([u8; 64]);
+
+thread_local! {
+    // the work buffer of noncegen_lanes, 256 KiB and more per lane, kept
+    // by each hashing thread for all of its tasks
+    static WORK_BUFFER: RefCell<Vec<Block>> = const { RefCell::new(Vec::new()) };
+}
+
 // cache:		    cache to save to
 // local_num:		thread number
 // numeric_id:		numeric account id
 // loc_startnonce	nonce to start generation at
 // local_nonces: 	number of nonces to generate
+//
+// The scalar noncegen, the lane code with a single u32 lane. It keeps its
+// work buffer on the heap and reuses it for every nonce and call.
 pub fn noncegen_rust(
     cache: &mut [u8],
     cache_offset: usize,
     numeric_id: u64,
     local_startnonce: u64,
     local_nonces: u64,
+) {
+    unsafe { noncegen_lanes::<u32>(cache, cache_offset, numeric_id, local_startnonce, local_nonces) }
+}
+
+// noncegen_rust on N nonces at once. The rounds of different nonces don't
+// depend on each other, so the cpu can overlap them.
+pub fn noncegen_rust_interleaved<const N: usize>(
+    cache: &mut [u8],
+    cache_offset: usize,
+    numeric_id: u64,
+    local_startnonce: u64,
+    local_nonces: u64,
+) {
+    unsafe { noncegen_lanes::<Portable<N>>(cache, cache_offset, numeric_id, local_startnonce, local_nonces) }
+}
+
+// noncegen_rust as it was before moving onto the lane code: one nonce at a
+// time through the scalar shabal256_fast, none of it shared with
+// noncegen_lanes and mshabal. The self-test compares the backends against
+// it, so a bug in the lane code can't pass by breaking both sides alike.
+pub fn noncegen_reference(
+    cache: &mut [u8],
+    cache_offset: usize,
+    numeric_id: u64,
+    local_startnonce: u64,
+    local_nonces: u64,
 ) {
     let numeric_id: [u32; 2] = unsafe { std::mem::transmute(numeric_id.to_be()) };
 
-    let mut buffer = [0u8; NONCE_SIZE];
+    let mut buffer = vec![0u8; NONCE_SIZE];
     let mut final_buffer = [0u8; HASH_SIZE];
 
-    // prepare termination strings
     let mut t1 = [0u32; MESSAGE_SIZE];
     t1[0..2].clone_from_slice(&numeric_id);
     t1[4] = 0x80;
@@ -37,60 +83,31 @@ pub fn noncegen_rust(
     t3[0] = 0x80;
 
     for n in 0..local_nonces {
-        // generate nonce numbers & change endianness
         let nonce: [u32; 2] = unsafe { std::mem::transmute((local_startnonce + n).to_be()) };
-
-        // store nonce numbers in relevant termination strings
         t1[2..4].clone_from_slice(&nonce);
         t2[10..12].clone_from_slice(&nonce);
 
-        // start shabal rounds
-
-        // 3 cases: first 128 rounds uses case 1 or 2, after that case 3
-        // case 1: first 128 rounds, hashes are even: use termination string 1
-        // case 2: first 128 rounds, hashes are odd: use termination string 2
-        // case 3: round > 128: use termination string 3
-        // round 1
         let hash = shabal256_fast(&[], &t1);
-
         buffer[NONCE_SIZE - HASH_SIZE..NONCE_SIZE].clone_from_slice(&hash);
         let hash = unsafe { std::mem::transmute::<[u8; 32], [u32; 8]>(hash) };
-
-        // store first hash into smart termination string 2
         t2[0..8].clone_from_slice(&hash);
-        // round 2 - 128
         for i in (NONCE_SIZE - HASH_CAP + HASH_SIZE..=NONCE_SIZE - HASH_SIZE)
             .rev()
             .step_by(HASH_SIZE)
         {
-            // check if msg can be divided into 512bit packages without a
-            // remainder
-            if i % 64 == 0 {
-                // last msg = seed + termination
-                let hash = &shabal256_fast(&buffer[i..NONCE_SIZE], &t1);
-                buffer[i - HASH_SIZE..i].clone_from_slice(hash);
-            } else {
-                // last msg = 256 bit data + seed + termination
-                let hash = &shabal256_fast(&buffer[i..NONCE_SIZE], &t2);
-                buffer[i - HASH_SIZE..i].clone_from_slice(hash);
-            }
+            let term = if i % 64 == 0 { &t1 } else { &t2 };
+            let hash = &shabal256_fast(&buffer[i..NONCE_SIZE], term);
+            buffer[i - HASH_SIZE..i].clone_from_slice(hash);
         }
-
-        // round 128-8192
         for i in (HASH_SIZE..=NONCE_SIZE - HASH_CAP).rev().step_by(HASH_SIZE) {
             let hash = &shabal256_fast(&buffer[i..i + HASH_CAP], &t3);
             buffer[i - HASH_SIZE..i].clone_from_slice(hash);
         }
-
-        // generate final hash
         final_buffer.clone_from_slice(&shabal256_fast(&buffer[0..NONCE_SIZE], &t1));
-
-        // XOR with final
         for i in 0..NONCE_SIZE {
             buffer[i] ^= final_buffer[i % HASH_SIZE];
         }
 
-        // PoC2 shuffle
         let cache_size = cache.len() / NONCE_SIZE;
         for i in 0..NUM_SCOOPS {
             let offset = i * cache_size * SCOOP_SIZE + (n as usize + cache_offset) * SCOOP_SIZE;
@@ -105,3 +122,179 @@ pub fn noncegen_rust(
         }
     }
 }
+
+// the simd versions of noncegen_rust, LANES nonces at once
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+pub fn noncegen_sse2(cache: &mut [u8], cache_offset: usize, numeric_id: u64, local_startnonce: u64, local_nonces: u64) {
+    unsafe { noncegen_lanes::<__m128i>(cache, cache_offset, numeric_id, local_startnonce, local_nonces) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx")]
+pub fn noncegen_avx(cache: &mut [u8], cache_offset: usize, numeric_id: u64, local_startnonce: u64, local_nonces: u64) {
+    unsafe { noncegen_lanes::<__m128i>(cache, cache_offset, numeric_id, local_startnonce, local_nonces) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+pub fn noncegen_avx2(cache: &mut [u8], cache_offset: usize, numeric_id: u64, local_startnonce: u64, local_nonces: u64) {
+    unsafe { noncegen_lanes::<__m256i>(cache, cache_offset, numeric_id, local_startnonce, local_nonces) }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx512f")]
+pub fn noncegen_avx512f(cache: &mut [u8], cache_offset: usize, numeric_id: u64, local_startnonce: u64, local_nonces: u64) {
+    unsafe { noncegen_lanes::<__m512i>(cache, cache_offset, numeric_id, local_startnonce, local_nonces) }
+}
+
+// the simd version for any target, 8 interleaved nonces get vectorised by
+// the compiler
+pub fn noncegen_portable(cache: &mut [u8], cache_offset: usize, numeric_id: u64, local_startnonce: u64, local_nonces: u64) {
+    noncegen_rust_interleaved::<8>(cache, cache_offset, numeric_id, local_startnonce, local_nonces)
+}
+
+// Hashes nonces LANES at a time, one per lane, words interleaved like
+// the messages of mshabal256_fast. Nonces left over go to noncegen_rust.
+#[inline(always)]
+unsafe fn noncegen_lanes<V: Lanes>(
+    cache: &mut [u8],
+    cache_offset: usize,
+    numeric_id: u64,
+    local_startnonce: u64,
+    local_nonces: u64,
+) {
+    let lanes = V::LANES;
+    let cache_size = cache.len() / NONCE_SIZE;
+    let id = numeric_id.to_be_bytes();
+    let id = [u32::from_ne_bytes(id[0..4].try_into().unwrap()), u32::from_ne_bytes(id[4..8].try_into().unwrap())];
+    let mut n = 0;
+
+    // every word is written before it is read, whatever the buffer held
+    let mut work = WORK_BUFFER.take();
+    let blocks = (NONCE_SIZE / 4 * size_of::<V>()).div_ceil(size_of::<Block>());
+    if work.len() < blocks {
+        work.resize(blocks, Block([0; 64]));
+    }
+
+    unsafe {
+        let zero = V::splat(0);
+        let buffer = std::slice::from_raw_parts_mut(work.as_mut_ptr() as *mut V, NONCE_SIZE / 4);
+        let mut final_hash = [zero; HASH_SIZE / 4];
+        let mut words = [0u32; 16];
+
+        // prepare termination strings
+        let mut t1 = [zero; MESSAGE_SIZE];
+        t1[0] = V::splat(id[0]);
+        t1[1] = V::splat(id[1]);
+        t1[4] = V::splat(0x80);
+
+        let mut t2 = [zero; MESSAGE_SIZE];
+        t2[8] = V::splat(id[0]);
+        t2[9] = V::splat(id[1]);
+        t2[12] = V::splat(0x80);
+
+        let mut t3 = [zero; MESSAGE_SIZE];
+        t3[0] = V::splat(0x80);
+
+        while n + lanes as u64 <= local_nonces {
+            // nonce numbers in big endian, one per lane
+            let (mut high, mut low) = ([0u32; 16], [0u32; 16]);
+            for (k, (high, low)) in high.iter_mut().zip(low.iter_mut()).take(lanes).enumerate() {
+                let x = (local_startnonce + n + k as u64).to_be_bytes();
+                *high = u32::from_ne_bytes(x[0..4].try_into().unwrap());
+                *low = u32::from_ne_bytes(x[4..8].try_into().unwrap());
+            }
+            t1[2] = V::from_slice(&high);
+            t1[3] = V::from_slice(&low);
+            t2[10] = t1[2];
+            t2[11] = t1[3];
+
+            // Every hash of the nonce, from the seed hash at the end of
+            // the buffer to the final one over all of it. A single call
+            // site keeps the inlined rounds from piling up on the stack of
+            // unoptimised builds.
+            for i in (0..=NONCE_SIZE).rev().step_by(HASH_SIZE) {
+                let (hashes, data) = buffer.split_at_mut(i / 4);
+                // 3 cases: the first 128 hashes use termination string 1
+                // (even) or 2 (odd), after that termination string 3
+                let (data, term) = if i == 0 || i % 64 == 0 && i > NONCE_SIZE - HASH_CAP {
+                    (&*data, &t1)
+                } else if i > NONCE_SIZE - HASH_CAP {
+                    (&*data, &t2)
+                } else {
+                    (&data[..HASH_CAP / 4], &t3)
+                };
+                let dst = if i == 0 { &mut final_hash[..] } else { &mut hashes[(i - HASH_SIZE) / 4..] };
+                mshabal256_fast(data, term, dst);
+                // termination string 2 starts with the seed hash
+                if i == NONCE_SIZE {
+                    t2[0..8].copy_from_slice(&dst[..8]);
+                }
+            }
+
+            for (i, x) in buffer.iter_mut().enumerate() {
+                *x = x.xor(final_hash[i % final_hash.len()]);
+            }
+
+            // unpack the lanes, PoC2 shuffle
+            let mut scoop = [[0u32; 16]; 16];
+            for i in 0..NUM_SCOOPS {
+                for (j, x) in buffer[i * SCOOP_SIZE / 4..(i + 1) * SCOOP_SIZE / 4].iter().enumerate() {
+                    x.write_to(&mut scoop[j]);
+                }
+                for k in 0..lanes {
+                    for (j, x) in scoop.iter().enumerate() {
+                        words[j] = x[k];
+                    }
+                    let nonce = (n as usize + k + cache_offset) * SCOOP_SIZE;
+                    let offset = i * cache_size * SCOOP_SIZE + nonce;
+                    let mirror_offset = (4095 - i) * cache_size * SCOOP_SIZE + nonce + HASH_SIZE;
+                    for j in 0..HASH_SIZE / 4 {
+                        cache[offset + j * 4..offset + j * 4 + 4].copy_from_slice(&words[j].to_ne_bytes());
+                        cache[mirror_offset + j * 4..mirror_offset + j * 4 + 4]
+                            .copy_from_slice(&words[j + 8].to_ne_bytes());
+                    }
+                }
+            }
+            n += lanes as u64;
+        }
+    }
+    WORK_BUFFER.set(work);
+
+    if n < local_nonces {
+        noncegen_rust(cache, cache_offset + n as usize, numeric_id, local_startnonce + n, local_nonces - n);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    type Noncegen = fn(&mut [u8], usize, u64, u64, u64);
+
+    // cargo test --release bench_noncegen_rust -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_noncegen_rust() {
+        let nonces = 32;
+        let noncegens: [(&str, Noncegen); 5] = [
+            ("reference", noncegen_reference),
+            ("scalar", noncegen_rust),
+            ("interleaved x2", noncegen_rust_interleaved::<2>),
+            ("interleaved x4", noncegen_rust_interleaved::<4>),
+            ("interleaved x8", noncegen_rust_interleaved::<8>),
+        ];
+        let mut expected = vec![0u8; nonces * NONCE_SIZE];
+        noncegen_reference(&mut expected, 0, 7900104405094198526, 1337, nonces as u64);
+        for (name, noncegen) in noncegens {
+            let mut cache = vec![0u8; nonces * NONCE_SIZE];
+            let start = Instant::now();
+            noncegen(&mut cache, 0, 7900104405094198526, 1337, nonces as u64);
+            let elapsed = start.elapsed().as_secs_f64();
+            assert!(cache == expected, "{} differs", name);
+            println!("{:<16}{:>8.0} nonces/m", name, nonces as f64 * 60.0 / elapsed);
+        }
+    }
+}
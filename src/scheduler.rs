@@ -3,219 +3,321 @@ use crate::buffer::PageAlignedByteBuffer;
 #[cfg(feature = "opencl")]
 use crate::gpu_hasher::{create_gpu_hasher_thread, GpuTask};
 #[cfg(feature = "opencl")]
-use crate::ocl::gpu_init;
+use crate::ocl::{gpu_init, GpuContext};
 use crate::plotter::{PlotterTask, NONCE_SIZE};
 #[cfg(feature = "opencl")]
 use crossbeam_channel::unbounded;
 use crossbeam_channel::{Receiver, Sender};
 use std::cmp::min;
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, channel};
+#[cfg(feature = "opencl")]
+use std::sync::Mutex;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 #[cfg(feature = "opencl")]
 use std::thread;
 
 const CPU_TASK_SIZE: u64 = 64;
 
+/// The hashing resources of one scheduler: the cpu threads of a worker group
+/// and, for the first group only, the gpus.
+pub struct Workers {
+    pub thread_pool: rayon::ThreadPool,
+    pub cpu_threads: usize,
+    #[cfg_attr(not(feature = "opencl"), allow(dead_code))]
+    pub gpus: bool,
+}
+
+/// Hands out the nonces of a plot to the schedulers of all worker groups,
+/// one buffer at a time and in plot order.
+pub struct NonceCursor {
+    next: AtomicU64,
+    hashed: AtomicU64,
+    nonces: u64,
+}
+
+impl NonceCursor {
+    pub fn new(progress: u64, nonces: u64) -> NonceCursor {
+        NonceCursor {
+            next: AtomicU64::new(progress),
+            hashed: AtomicU64::new(progress),
+            nonces,
+        }
+    }
+
+    // offset and count of the next up to `max` nonces, None once all are claimed
+    fn claim(&self, max: u64) -> Option<(u64, u64)> {
+        let start = self.next.fetch_add(max, Ordering::SeqCst);
+        if start >= self.nonces {
+            return None;
+        }
+        Some((start, min(max, self.nonces - start)))
+    }
+
+    fn exhausted(&self) -> bool {
+        self.next.load(Ordering::SeqCst) >= self.nonces
+    }
+
+    // counts hashed nonces, true when the last ones are in
+    fn hashed(&self, nonces: u64) -> bool {
+        self.hashed.fetch_add(nonces, Ordering::SeqCst) + nonces == self.nonces
+    }
+}
+
+/// What a worker reports to its scheduler, by its index in the registry.
+pub enum WorkerMessage {
+    /// The worker takes another task.
+    Ready(usize),
+    /// Nonces of the worker's task are in the buffer.
+    Hashed(usize, u64),
+}
+
+// `nonces` nonces from `start_nonce` on, hashed into the buffer at nonce
+// offset `offset`
+struct Task {
+    cache: *mut u8,
+    cache_size: u64,
+    offset: u64,
+    numeric_id: u64,
+    start_nonce: u64,
+    nonces: u64,
+}
+
+/// A hashing backend the scheduler hands tasks to. It reports back with
+/// `WorkerMessage`s.
+trait Worker {
+    fn name(&self) -> String;
+    // tasks it works on at once
+    fn slots(&self) -> usize;
+    // nonces of its next task out of the `remaining` ones of the buffer,
+    // `shared` if other workers hash into the same buffer
+    fn task_size(&self, remaining: u64, shared: bool) -> u64;
+    fn start(&self, task: Task);
+    // no more tasks coming
+    fn stop(&self) {}
+}
+
+// the cpu threads of a worker group
+struct CpuPool {
+    id: usize,
+    thread_pool: rayon::ThreadPool,
+    threads: usize,
+    simd_ext: SimdExtension,
+    tx: mpsc::Sender<WorkerMessage>,
+}
+
+impl Worker for CpuPool {
+    fn name(&self) -> String {
+        "cpu".to_string()
+    }
+
+    fn slots(&self) -> usize {
+        self.threads
+    }
+
+    fn task_size(&self, remaining: u64, _shared: bool) -> u64 {
+        min(CPU_TASK_SIZE, remaining)
+    }
+
+    fn start(&self, task: Task) {
+        if task.nonces == 0 {
+            return;
+        }
+        self.thread_pool.spawn(hash_cpu(
+            self.tx.clone(),
+            self.id,
+            CpuTask {
+                cache: SafePointer { ptr: task.cache },
+                cache_size: task.cache_size as usize,
+                chunk_offset: task.offset as usize,
+                numeric_id: task.numeric_id,
+                local_startnonce: task.start_nonce,
+                local_nonces: task.nonces,
+            },
+            self.simd_ext.clone(),
+        ));
+    }
+}
+
+// a gpu and the thread feeding it. Its hasher thread hashes one task while
+// transferring the one before, a task of 0 nonces transfers the last one.
+#[cfg(feature = "opencl")]
+struct Gpu {
+    name: String,
+    worksize: u64,
+    tx: Sender<Option<GpuTask>>,
+}
+
+#[cfg(feature = "opencl")]
+impl Gpu {
+    fn spawn(id: usize, name: &str, context: Arc<Mutex<GpuContext>>, tx: mpsc::Sender<WorkerMessage>) -> Gpu {
+        let worksize = context.lock().unwrap().worksize as u64;
+        let (tx_tasks, rx_tasks) = unbounded();
+        thread::spawn(create_gpu_hasher_thread(id, context, tx, rx_tasks));
+        Gpu {
+            name: format!("gpu {}", name),
+            worksize,
+            tx: tx_tasks,
+        }
+    }
+}
+
+#[cfg(feature = "opencl")]
+impl Worker for Gpu {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn slots(&self) -> usize {
+        1
+    }
+
+    // the tail of a buffer is split with the other workers
+    fn task_size(&self, remaining: u64, shared: bool) -> u64 {
+        let task_size = min(self.worksize, remaining);
+        if task_size < self.worksize && shared && task_size > CPU_TASK_SIZE {
+            task_size / 2
+        } else {
+            task_size
+        }
+    }
+
+    // sent even without nonces, see Gpu
+    fn start(&self, task: Task) {
+        self.tx
+            .send(Some(GpuTask {
+                cache: SafePointer { ptr: task.cache },
+                cache_size: task.cache_size,
+                chunk_offset: task.offset,
+                numeric_id: task.numeric_id,
+                local_startnonce: task.start_nonce,
+                local_nonces: task.nonces,
+            }))
+            .unwrap();
+    }
+
+    fn stop(&self) {
+        self.tx.send(None).unwrap();
+    }
+}
+
+/// Nonces each worker of a scheduler hashed, by name.
+pub struct SchedulerStats {
+    // time spent waiting for empty buffers
+    pub stall: Duration,
+    pub hashed: Vec<(String, u64)>,
+}
+
+/// Hashes the buffers of one worker group. Full buffers are passed on with
+/// the nonce offset they start at, groups finish them in any order.
 pub fn create_scheduler_thread(
     task: Arc<PlotterTask>,
-    thread_pool: rayon::ThreadPool,
-    mut nonces_hashed: u64,
+    workers: Workers,
+    cursor: Arc<NonceCursor>,
     pb: Option<indicatif::ProgressBar>,
     rx_empty_buffers: Receiver<PageAlignedByteBuffer>,
-    tx_buffers_to_writer: Sender<PageAlignedByteBuffer>,
+    tx_buffers_to_writer: Sender<(u64, PageAlignedByteBuffer)>,
     simd_ext: SimdExtension,
-) -> impl FnOnce() {
+) -> impl FnOnce() -> SchedulerStats {
     move || {
-
         let (tx, rx) = channel();
 
+        // the registry, workers are known by their index
+        let mut registry: Vec<Box<dyn Worker>> = Vec::new();
         #[cfg(feature = "opencl")]
-        let gpu_contexts = match &task.gpus {
-            Some(x) => Some(gpu_init(&x, task.zcb)),
-            None => None,
-        };
-
-        #[cfg(feature = "opencl")]
-        let gpus = match gpu_contexts {
-            Some(x) => x,
-            None => Vec::new(),
-        };
-        #[cfg(feature = "opencl")]
-        let mut gpu_threads = Vec::new();
-        #[cfg(feature = "opencl")]
-        let mut gpu_channels = Vec::new();
-
-        #[cfg(feature = "opencl")]
-        for (i, gpu) in gpus.iter().enumerate() {
-            gpu_channels.push(unbounded());
-            gpu_threads.push(thread::spawn({
-                create_gpu_hasher_thread(
-                    (i + 1) as u8,
-                    gpu.clone(),
-                    tx.clone(),
-                    gpu_channels.last().unwrap().1.clone(),
-                )
+        if let Some(gpus) = task.gpus.as_ref().filter(|_| workers.gpus) {
+            for (gpu, context) in gpus.iter().zip(gpu_init(gpus, task.zcb)) {
+                registry.push(Box::new(Gpu::spawn(registry.len(), gpu, context, tx.clone())));
+            }
+        }
+        if workers.cpu_threads > 0 {
+            registry.push(Box::new(CpuPool {
+                id: registry.len(),
+                thread_pool: workers.thread_pool,
+                threads: workers.cpu_threads,
+                simd_ext,
+                tx: tx.clone(),
             }));
         }
+        let shared = registry.len() > 1;
+        let mut hashed = vec![0u64; registry.len()];
+
+        // time spent waiting for the other side of the buffer pipeline
+        let mut stall = Duration::ZERO;
+        while !cursor.exhausted() {
+            let wait = Instant::now();
+            let buffer = match rx_empty_buffers.recv() {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            stall += wait.elapsed();
 
-        for buffer in rx_empty_buffers {
             let mut_bs = &buffer.get_buffer();
             let mut bs = mut_bs.lock().unwrap();
             let buffer_size = (*bs).len() as u64;
-            let nonces_to_hash = min(buffer_size / NONCE_SIZE, task.nonces - nonces_hashed);
+            let (nonces_hashed, nonces_to_hash) = match cursor.claim(buffer_size / NONCE_SIZE) {
+                Some(x) => x,
+                None => break,
+            };
 
             let mut requested = 0u64;
             let mut processed = 0u64;
 
-            #[cfg(feature = "opencl")]
-            for (i, gpu) in gpus.iter().enumerate() {
-
-                let gpu = gpu.lock().unwrap();
-                let task_size = min(gpu.worksize as u64, nonces_to_hash - requested);
-                if task_size > 0 {
-                    gpu_channels[i]
-                        .0
-                        .send(Some(GpuTask {
-                            cache: SafePointer {
-                                ptr: bs.as_mut_ptr(),
-                            },
-                            cache_size: buffer_size / NONCE_SIZE,
-                            chunk_offset: requested,
-                            numeric_id: task.numeric_id,
-                            local_startnonce: task.start_nonce + nonces_hashed + requested,
-                            local_nonces: task_size,
-                        }))
-                        .unwrap();
-                }
-                requested += task_size;
+            // the next task of the buffer to a worker
+            let mut hand_out = |worker: &dyn Worker| {
+                let nonces = worker.task_size(nonces_to_hash - requested, shared);
+                worker.start(Task {
+                    cache: bs.as_mut_ptr(),
+                    cache_size: buffer_size / NONCE_SIZE,
+                    offset: requested,
+                    numeric_id: task.numeric_id,
+                    start_nonce: task.start_nonce + nonces_hashed + requested,
+                    nonces,
+                });
+                requested += nonces;
+            };
 
-            }
-
-            for _ in 0..task.cpu_threads {
-                let task_size = min(CPU_TASK_SIZE, nonces_to_hash - requested);
-                if task_size > 0 {
-                    let task = hash_cpu(
-                        tx.clone(),
-                        CpuTask {
-                            cache: SafePointer {
-                                ptr: bs.as_mut_ptr(),
-                            },
-                            cache_size: (buffer_size / NONCE_SIZE) as usize,
-                            chunk_offset: requested as usize,
-                            numeric_id: task.numeric_id,
-                            local_startnonce: task.start_nonce + nonces_hashed + requested,
-                            local_nonces: task_size,
-                        },
-                        simd_ext.clone(),
-                    );
-                    thread_pool.spawn(task);
+            for worker in &registry {
+                for _ in 0..worker.slots() {
+                    hand_out(worker.as_ref());
                 }
-                requested += task_size;
             }
 
-            let rx = &rx;
-            for msg in rx {
-                match msg.1 {
-
-                    1 => {
-                        let task_size = match msg.0 {
-                            0 => {
-
-                                let task_size = min(CPU_TASK_SIZE, nonces_to_hash - requested);
-                                if task_size > 0 {
-                                    let task = hash_cpu(
-                                        tx.clone(),
-                                        CpuTask {
-                                            cache: SafePointer {
-                                                ptr: bs.as_mut_ptr(),
-                                            },
-                                            cache_size: (buffer_size / NONCE_SIZE) as usize,
-                                            chunk_offset: requested as usize,
-                                            numeric_id: task.numeric_id,
-                                            local_startnonce: task.start_nonce
-                                                + nonces_hashed
-                                                + requested,
-                                            local_nonces: task_size,
-                                        },
-                                        simd_ext.clone(),
-                                    );
-                                    thread_pool.spawn(task);
-                                }
-                                task_size
-                            }
-                            _ => {
-
-                                #[cfg(feature = "opencl")]
-                                let gpu = gpus[(msg.0 - 1) as usize].lock().unwrap();
-                                #[cfg(feature = "opencl")]
-                                let task_size =
-                                    min(gpu.worksize as u64, nonces_to_hash - requested);
-
-                                #[cfg(feature = "opencl")]
-                                let task_size = if task_size < gpu.worksize as u64
-                                    && task.cpu_threads > 0
-                                    && task_size > CPU_TASK_SIZE
-                                {
-                                    task_size / 2
-                                } else {
-                                    task_size
-                                };
-
-                                #[cfg(not(feature = "opencl"))]
-                                let task_size = 0;
-
-                                #[cfg(feature = "opencl")]
-                                gpu_channels[(msg.0 - 1) as usize]
-                                    .0
-                                    .send(Some(GpuTask {
-                                        cache: SafePointer {
-                                            ptr: bs.as_mut_ptr(),
-                                        },
-                                        cache_size: buffer_size / NONCE_SIZE,
-                                        chunk_offset: requested,
-                                        numeric_id: task.numeric_id,
-                                        local_startnonce: task.start_nonce
-                                            + nonces_hashed
-                                            + requested,
-                                        local_nonces: task_size,
-                                    }))
-                                    .unwrap();
-                                task_size
-                            }
-                        };
-
-                        requested += task_size;
-
-                    }
-
-                    0 => {
-                        processed += msg.2;
+            for msg in &rx {
+                match msg {
+                    WorkerMessage::Ready(id) => hand_out(registry[id].as_ref()),
+                    WorkerMessage::Hashed(id, nonces) => {
+                        processed += nonces;
+                        hashed[id] += nonces;
                         if let Some(pb) = &pb {
-                            pb.inc(msg.2 * NONCE_SIZE);
+                            pb.inc(nonces * NONCE_SIZE);
                         }
                     }
-                    _ => {}
                 }
                 if processed == nonces_to_hash {
                     break;
                 }
             }
 
-            nonces_hashed += nonces_to_hash;
-
-            tx_buffers_to_writer.send(buffer).unwrap();
-
-            if task.nonces == nonces_hashed {
-                if let Some(pb) = &pb {
-                    pb.finish_with_message("Hasher done.");
-                }
-
-                #[cfg(feature = "opencl")]
-                for gpu in &gpu_channels {
-                    gpu.0.send(None).unwrap();
-                }
+            // the writer aborted
+            if tx_buffers_to_writer.send((nonces_hashed, buffer)).is_err() {
                 break;
             }
+
+            if cursor.hashed(nonces_to_hash)
+                && let Some(pb) = &pb
+            {
+                pb.finish_with_message("Hasher done.");
+            }
+        }
+
+        for worker in &registry {
+            worker.stop();
+        }
+        SchedulerStats {
+            stall,
+            hashed: registry.iter().map(|x| x.name()).zip(hashed).collect(),
         }
     }
 }
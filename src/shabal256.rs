@@ -1,50 +1,141 @@
-use std::slice::from_raw_parts;
+// The scalar Shabal-256, the reference the lane code is tested against.
+use std::cmp::min;
 
-const A_INIT: [u32; 12] = [
+pub const A_INIT: [u32; 12] = [
     0x52F84552, 0xE54B7999, 0x2D8EE3EC, 0xB9645191, 0xE0078B86, 0xBB7C44C9, 0xD2B5C1CA, 0xB0D2EB8C,
     0x14CE5A45, 0x22AF50DC, 0xEFFDBC6B, 0xEB21B74A,
 ];
 
-const B_INIT: [u32; 16] = [
+pub const B_INIT: [u32; 16] = [
     0xB555C6EE, 0x3E710596, 0xA72A652F, 0x9301515F, 0xDA28C1FA, 0x696FD868, 0x9CB6BF72, 0x0AFE4002,
     0xA6E03615, 0x5138C1D4, 0xBE216306, 0xB38B8890, 0x3EA8B96B, 0x3299ACE4, 0x30924DD4, 0x55CB34A5,
 ];
 
-const C_INIT: [u32; 16] = [
+pub const C_INIT: [u32; 16] = [
     0xB405F031, 0xC4233EBA, 0xB3733979, 0xC0DD9D55, 0xC51C28AE, 0xA327B8E1, 0x56C56167, 0xED614433,
     0x88B59D60, 0x60E2CEBA, 0x758B4B8B, 0x83E82A7F, 0xBC968828, 0xE6E00BF7, 0xBA839E55, 0x9B491C60,
 ];
 
-pub fn shabal256_fast(data: &[u8], term: &[u32; 16]) -> [u8; 32] {
-    let mut a = A_INIT;
-    let mut b = B_INIT;
-    let mut c = C_INIT;
-    let mut w_high = 0u32;
-    let mut w_low = 1u32;
-    let mut num = data.len() >> 6;
-    let mut ptr = 0;
-    let data_ptr = data.as_ptr() as *const u32;
-    let data = unsafe { from_raw_parts(data_ptr, data.len() / 4) };
+/// Shabal-256 over data handed in piece by piece.
+#[derive(Clone)]
+pub struct Shabal256 {
+    a: [u32; 12],
+    b: [u32; 16],
+    c: [u32; 16],
+    w_low: u32,
+    w_high: u32,
+    // start of a block, waiting for the rest
+    buffer: [u8; 64],
+    buffered: usize,
+}
+
+impl Default for Shabal256 {
+    fn default() -> Shabal256 {
+        Shabal256::new()
+    }
+}
+
+impl Shabal256 {
+    pub fn new() -> Shabal256 {
+        Shabal256 {
+            a: A_INIT,
+            b: B_INIT,
+            c: C_INIT,
+            w_low: 1,
+            w_high: 0,
+            buffer: [0; 64],
+            buffered: 0,
+        }
+    }
 
-    while num > 0 {
-        input_block_add(&mut b, &data[ptr..]);
-        xor_w(&mut a, w_low, w_high);
-        apply_p(&mut a, &mut b, &c, &data[ptr..]);
-        input_block_sub(&mut c, &data[ptr..]);
-        swap_bc(&mut b, &mut c);
-        incr_w(&mut w_low, &mut w_high);
-        ptr = ptr.wrapping_add(16);
-        num = num.wrapping_sub(1);
+    pub fn update(&mut self, mut data: &[u8]) {
+        if self.buffered > 0 {
+            let n = min(64 - self.buffered, data.len());
+            self.buffer[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+            if self.buffered < 64 {
+                return;
+            }
+            let block = words(&self.buffer, u32::from_le_bytes);
+            self.compress(&block);
+            self.buffered = 0;
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(&words(block, u32::from_le_bytes));
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
     }
-    input_block_add(&mut b, term);
-    xor_w(&mut a, w_low, w_high);
-    apply_p(&mut a, &mut b, &c, term);
-    for _ in 0..3 {
-        swap_bc(&mut b, &mut c);
-        xor_w(&mut a, w_low, w_high);
-        apply_p(&mut a, &mut b, &c, term);
+
+    /// The hash of everything passed to `update`.
+    pub fn finalize(mut self) -> [u8; 32] {
+        // the last block is padded with 0x80 and zeros, a whole one if need be
+        self.buffer[self.buffered] = 0x80;
+        self.buffer[self.buffered + 1..].fill(0);
+        let term = words(&self.buffer, u32::from_le_bytes);
+        let mut hash = [0u8; 32];
+        for (hash, x) in hash.chunks_exact_mut(4).zip(self.finish(&term)) {
+            hash.copy_from_slice(&x.to_le_bytes());
+        }
+        hash
+    }
+
+    fn compress(&mut self, m: &[u32; 16]) {
+        input_block_add(&mut self.b, m);
+        xor_w(&mut self.a, self.w_low, self.w_high);
+        apply_p(&mut self.a, &mut self.b, &self.c, m);
+        input_block_sub(&mut self.c, m);
+        swap_bc(&mut self.b, &mut self.c);
+        incr_w(&mut self.w_low, &mut self.w_high);
+    }
+
+    // the last block and the 3 extra rounds, the hash is the end of b
+    fn finish(mut self, term: &[u32; 16]) -> [u32; 8] {
+        input_block_add(&mut self.b, term);
+        xor_w(&mut self.a, self.w_low, self.w_high);
+        apply_p(&mut self.a, &mut self.b, &self.c, term);
+        for _ in 0..3 {
+            swap_bc(&mut self.b, &mut self.c);
+            xor_w(&mut self.a, self.w_low, self.w_high);
+            apply_p(&mut self.a, &mut self.b, &self.c, term);
+        }
+        self.b[8..].try_into().unwrap()
     }
-    unsafe { *(b[8..16].as_ptr() as *const [u8; 32]) }
+}
+
+/// Shabal-256 of `data`.
+pub fn shabal256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Shabal256::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Shabal-256 the way plotting calls it: whole 64 byte blocks of `data`
+/// followed by a termination block that already holds the rest of the
+/// message and the padding. Words are in native byte order.
+pub fn shabal256_fast(data: &[u8], term: &[u32; 16]) -> [u8; 32] {
+    let mut hasher = Shabal256::new();
+    for block in data.chunks_exact(64) {
+        hasher.compress(&words(block, u32::from_ne_bytes));
+    }
+    let mut hash = [0u8; 32];
+    for (hash, x) in hash.chunks_exact_mut(4).zip(hasher.finish(term)) {
+        hash.copy_from_slice(&x.to_ne_bytes());
+    }
+    hash
+}
+
+// the 16 words of a block, data needn't be aligned for u32
+#[inline(always)]
+fn words(block: &[u8], from_bytes: fn([u8; 4]) -> u32) -> [u32; 16] {
+    let mut m = [0u32; 16];
+    for (m, x) in m.iter_mut().zip(block.chunks_exact(4)) {
+        *m = from_bytes(x.try_into().unwrap());
+    }
+    m
 }
 
 #[inline(always)]
@@ -123,6 +214,7 @@ fn apply_p(a: &mut [u32; 12], b: &mut [u32; 16], c: &[u32; 16], data: &[u32]) {
         .wrapping_add(c[14]);
 }
 
+#[allow(clippy::too_many_arguments)]
 #[inline(always)]
 fn perm_elt(
     a: &mut [u32; 12],
@@ -260,4 +352,34 @@ mod test {
         };
         assert_eq!(hash_b, TEST_B_RESULT);
     }
+
+    #[test]
+    fn test_vectors() {
+        let message_b = b"abcdefghijklmnopqrstuvwxyz-0123456789-ABCDEFGHIJKLMNOPQRSTUVWXYZ-0123456789-abcdefghijklmnopqrstuvwxyz";
+        assert_eq!(super::shabal256(&[0u8; 64]), TEST_A_RESULT);
+        assert_eq!(super::shabal256(message_b), TEST_B_RESULT);
+        assert_eq!(
+            super::shabal256(&[]),
+            [
+                0xAE, 0xC7, 0x50, 0xD1, 0x1F, 0xEE, 0xE9, 0xF1, 0x62, 0x71, 0x92, 0x2F, 0xBA, 0xF5, 0xA9,
+                0xBE, 0x14, 0x2F, 0x62, 0x01, 0x9E, 0xF8, 0xD7, 0x20, 0xF8, 0x58, 0x94, 0x00, 0x70, 0x88,
+                0x90, 0x14,
+            ]
+        );
+    }
+
+    #[test]
+    fn incremental() {
+        let data: Vec<u8> = (0..300u32).map(|x| (x * 7 + x / 5) as u8).collect();
+        for len in [0, 1, 63, 64, 65, 127, 128, 200, 300] {
+            let expected = super::shabal256(&data[..len]);
+            for step in [1, 3, 64, 100] {
+                let mut hasher = Shabal256::new();
+                for chunk in data[..len].chunks(step) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(hasher.finalize(), expected, "len {} in steps of {}", len, step);
+            }
+        }
+    }
 }
@@ -0,0 +1,182 @@
+use humanize_rs::bytes::Bytes;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// bytes a full bucket holds, in seconds of the rate
+const BURST_SECONDS: f64 = 1.0;
+// longest sleep before the rate is looked at again, keeps runtime changes responsive
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+const RATE_FILE_POLL: Duration = Duration::from_secs(1);
+
+/// Token bucket limiting the bytes per second written to one disk. The rate
+/// can be changed at any time, 0 means unlimited.
+pub struct RateLimiter {
+    // shared with the rate file watcher
+    rate: Arc<AtomicU64>,
+    bucket: Mutex<Bucket>,
+    // dropped with the limiter, which stops the watcher
+    _watcher: Option<Sender<()>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate: Arc::new(AtomicU64::new(rate)),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64 * BURST_SECONDS,
+                last: Instant::now(),
+            }),
+            _watcher: None,
+        }
+    }
+
+    /// A limiter starting at `rate`. With a `rate_file` the limit follows the
+    /// rate in that file for as long as the limiter lives.
+    pub fn with_rate_file(rate: u64, rate_file: Option<&Path>) -> RateLimiter {
+        let mut limiter = RateLimiter::new(rate);
+        if let Some(rate_file) = rate_file {
+            limiter._watcher = Some(watch_rate_file(rate_file.to_path_buf(), limiter.rate.clone()));
+        }
+        limiter
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Takes `bytes` tokens, sleeping until the bucket has refilled. Writes
+    /// larger than the bucket go into debt, which later writes wait out.
+    pub fn acquire(&self, bytes: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens -= bytes as f64;
+        while let Some(wait) = self.refill(&mut bucket, Instant::now()) {
+            thread::sleep(wait.min(MAX_SLEEP));
+        }
+    }
+
+    // refills the bucket up to `now`, returns how long it takes to get out
+    // of debt at the current rate
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> Option<Duration> {
+        let rate = self.rate() as f64;
+        if rate == 0.0 {
+            bucket.tokens = 0.0;
+            bucket.last = now;
+            return None;
+        }
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * rate)
+            .min(rate * BURST_SECONDS);
+        bucket.last = now;
+        if bucket.tokens >= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(-bucket.tokens / rate))
+    }
+
+    /// Progress bar message showing the current limit.
+    pub fn describe(&self) -> String {
+        match self.rate() {
+            0 => String::new(),
+            x => format!("max {:.1} MiB/s", x as f64 / 1024.0 / 1024.0),
+        }
+    }
+}
+
+/// Parses a rate like `80MiB/s` or `80MiB`, `0` disables the limit.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let s = s.strip_suffix("/s").unwrap_or(s);
+    s.parse::<Bytes>()
+        .map(|x| x.size() as u64)
+        .map_err(|_| format!("invalid rate '{}', expected e.g. 80MiB/s", s))
+}
+
+// re-reads the rate file every second until the returned sender is dropped,
+// keeps the last valid rate on errors
+fn watch_rate_file(path: PathBuf, rate: Arc<AtomicU64>) -> Sender<()> {
+    let (tx, rx) = channel::<()>();
+    thread::spawn(move || {
+        let mut last = None;
+        loop {
+            if let Ok(content) = std::fs::read_to_string(&path)
+                && last.as_ref() != Some(&content)
+            {
+                match parse_rate(&content) {
+                    Ok(x) => rate.store(x, Ordering::Relaxed),
+                    Err(e) => eprintln!("Warning: {} in {}", e, path.display()),
+                }
+                last = Some(content);
+            }
+            if rx.recv_timeout(RATE_FILE_POLL) != Err(RecvTimeoutError::Timeout) {
+                break;
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::ScratchPath;
+
+    #[test]
+    fn rate_parsing() {
+        assert_eq!(parse_rate("80MiB/s"), Ok(80 * 1024 * 1024));
+        assert_eq!(parse_rate("1MB"), Ok(1_000_000));
+        assert_eq!(parse_rate("0"), Ok(0));
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(1_000_000);
+        let mut bucket = limiter.bucket.lock().unwrap();
+        let start = bucket.last;
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // a full bucket lets a second's worth through at once
+        bucket.tokens -= 1_000_000.0;
+        assert_eq!(limiter.refill(&mut bucket, at(0)), None);
+        bucket.tokens -= 200_000.0;
+        assert_eq!(limiter.refill(&mut bucket, at(0)), Some(Duration::from_millis(200)));
+        assert_eq!(limiter.refill(&mut bucket, at(150)), Some(Duration::from_millis(50)));
+        assert_eq!(limiter.refill(&mut bucket, at(200)), None);
+
+        // idle time refills no more than the burst
+        assert_eq!(limiter.refill(&mut bucket, at(10_000)), None);
+        bucket.tokens -= 1_500_000.0;
+        assert_eq!(limiter.refill(&mut bucket, at(10_000)), Some(Duration::from_millis(500)));
+
+        // lifting the limit releases a waiting write
+        bucket.tokens -= 10_000_000.0;
+        limiter.set_rate(0);
+        assert_eq!(limiter.refill(&mut bucket, at(10_000)), None);
+        assert_eq!(bucket.tokens, 0.0);
+    }
+
+    #[test]
+    fn rate_file() {
+        let path = ScratchPath::temp("rate");
+        std::fs::write(&path, "2MiB/s").unwrap();
+        let limiter = RateLimiter::with_rate_file(1, Some(&path));
+        let start = Instant::now();
+        while limiter.rate() != 2 * 1024 * 1024 {
+            assert!(start.elapsed() < Duration::from_secs(60));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::plotter::{NUM_SCOOPS, SCOOP_SIZE};
+use crate::writer::PlotFile;
+use indicatif::ProgressBar;
+use io_uring::{opcode, types, IoUring};
+use std::cmp::min;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::AsRawFd;
+
+// number of fixed buffer slots, each slot covers up to 1 GiB of a plotting buffer
+const REGISTERED_BUFFER_SLOTS: u32 = 64;
+// kernel limit for a single registered buffer
+const MAX_REGISTERED_BUFFER_SIZE: usize = 1 << 30;
+// linux transfers at most this many bytes per write, larger scoop regions
+// complete as short writes and are resubmitted
+const MAX_WRITE_SIZE: u64 = 0x7fff_f000;
+
+// a scoop region write (or the rest of it after a short write)
+struct Chunk {
+    local_addr: u64,
+    seek_addr: u64,
+    len: u64,
+}
+
+/// Writes plotting buffers through io_uring. All scoop region writes of a
+/// buffer are submitted as one batch, bounded by the queue depth, using
+/// registered buffers where the kernel allows it.
+pub struct UringWriter {
+    ring: IoUring,
+    depth: u32,
+    // (address, length) of every registered slot, `None` if the kernel
+    // doesn't support sparse buffer registration
+    registered: Option<Vec<(usize, usize)>>,
+    // set by errors that leave the ring unable to write further buffers
+    unusable: bool,
+}
+
+// errors showing that io_uring can't write the plot file at all, any other
+// error only fails the buffer at hand
+fn unsupported(e: &Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL))
+}
+
+impl UringWriter {
+    pub fn new(depth: u32) -> Result<UringWriter, Error> {
+        let ring = IoUring::new(depth)?;
+        let registered = match ring.submitter().register_buffers_sparse(REGISTERED_BUFFER_SLOTS) {
+            Ok(_) => Some(Vec::new()),
+            Err(_) => None,
+        };
+        Ok(UringWriter {
+            ring,
+            depth,
+            registered,
+            unusable: false,
+        })
+    }
+
+    /// False once an error showed that the ring can't write this file,
+    /// later buffers then go through blocking writes.
+    pub fn usable(&self) -> bool {
+        !self.unusable
+    }
+
+    // registers a plotting buffer in 1 GiB slots, if it isn't registered yet
+    // and there are slots left. Unregistered parts are written with plain writes.
+    fn register(&mut self, bs: &[u8]) {
+        let table = match self.registered.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        let base = bs.as_ptr() as usize;
+        if table.iter().any(|&(addr, _)| addr == base) {
+            return;
+        }
+        let pieces = bs.len().div_ceil(MAX_REGISTERED_BUFFER_SIZE);
+        if table.len() + pieces > REGISTERED_BUFFER_SLOTS as usize {
+            return;
+        }
+        let iovecs: Vec<libc::iovec> = (0..pieces)
+            .map(|i| {
+                let offset = i * MAX_REGISTERED_BUFFER_SIZE;
+                libc::iovec {
+                    iov_base: bs[offset..].as_ptr() as *mut libc::c_void,
+                    iov_len: min(MAX_REGISTERED_BUFFER_SIZE, bs.len() - offset),
+                }
+            })
+            .collect();
+        // plotting buffers live until the plotter is done, the ring is
+        // dropped together with the writer thread before that
+        let result = unsafe {
+            self.ring
+                .submitter()
+                .register_buffers_update(table.len() as u32, &iovecs, None)
+        };
+        match result {
+            Ok(_) => table.extend(iovecs.iter().map(|x| (x.iov_base as usize, x.iov_len))),
+            Err(_) => self.registered = None,
+        }
+    }
+
+    fn slot(&self, addr: usize, len: usize) -> Option<u16> {
+        self.registered.as_ref()?.iter().position(|&(start, size)| {
+            start <= addr && addr + len <= start + size
+        }).map(|x| x as u16)
+    }
+
+    /// Writes `nonces_to_write` nonces of every scoop region of `bs` to their
+    /// place in the plot file and waits until all writes have completed.
+    pub fn write_buffer(
+        &mut self,
+        file: &PlotFile,
+        bs: &[u8],
+        plot_nonces: u64,
+        nonces_written: u64,
+        nonces_to_write: u64,
+        pb: &Option<ProgressBar>,
+    ) -> Result<(), Error> {
+        self.register(bs);
+        let buffer_nonces = bs.len() as u64 / (NUM_SCOOPS * SCOOP_SIZE);
+        let fd = types::Fd(file.file.as_raw_fd());
+        let start_addr = file.base_offset + nonces_written * SCOOP_SIZE;
+
+        let mut chunks: Vec<Chunk> = (0..NUM_SCOOPS)
+            .map(|scoop| Chunk {
+                local_addr: scoop * buffer_nonces * SCOOP_SIZE,
+                seek_addr: scoop * plot_nonces * SCOOP_SIZE + start_addr,
+                len: nonces_to_write * SCOOP_SIZE,
+            })
+            .collect();
+
+        let mut next = 0;
+        let mut retry = Vec::new();
+        let mut in_flight = 0;
+        let mut error = None;
+        let mut progressed = 0u64;
+        let mut completions = Vec::with_capacity(self.depth as usize);
+
+        while in_flight > 0 || (error.is_none() && (next < chunks.len() || !retry.is_empty())) {
+            while error.is_none() && in_flight < self.depth {
+                let idx = match retry.pop() {
+                    Some(x) => x,
+                    None if next < chunks.len() => {
+                        next += 1;
+                        next - 1
+                    }
+                    None => break,
+                };
+                let chunk: &Chunk = &chunks[idx];
+                let ptr = bs[chunk.local_addr as usize..].as_ptr();
+                let len = min(chunk.len, MAX_WRITE_SIZE) as u32;
+                if let Some(limiter) = &file.limiter {
+                    limiter.acquire(u64::from(len));
+                }
+                let entry = match self.slot(ptr as usize, len as usize) {
+                    Some(slot) => opcode::WriteFixed::new(fd, ptr, len, slot)
+                        .offset(chunk.seek_addr)
+                        .build(),
+                    None => opcode::Write::new(fd, ptr, len)
+                        .offset(chunk.seek_addr)
+                        .build(),
+                };
+                unsafe {
+                    self.ring
+                        .submission()
+                        .push(&entry.user_data(idx as u64))
+                        .expect("io_uring submission queue full");
+                }
+                in_flight += 1;
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // short of resources or completion space, reap and submit again
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EBUSY)) => (),
+                Err(e) => {
+                    // writes may still be in flight, their completions would
+                    // mix with those of the next buffer
+                    self.unusable = true;
+                    return Err(e);
+                }
+            }
+            completions.extend(self.ring.completion().map(|x| (x.user_data(), x.result())));
+
+            for (idx, result) in completions.drain(..) {
+                in_flight -= 1;
+                let chunk = &mut chunks[idx as usize];
+                if result < 0 {
+                    error.get_or_insert(Error::from_raw_os_error(-result));
+                } else if result == 0 {
+                    error.get_or_insert(Error::new(ErrorKind::WriteZero, "io_uring wrote 0 bytes"));
+                } else {
+                    let written = result as u64;
+                    progressed += written;
+                    if let Some(pb) = pb {
+                        pb.inc(written);
+                    }
+                    if written < chunk.len {
+                        chunk.local_addr += written;
+                        chunk.seek_addr += written;
+                        chunk.len -= written;
+                        retry.push(idx as usize);
+                    }
+                }
+            }
+        }
+
+        match error {
+            Some(e) => {
+                if unsupported(&e) {
+                    self.unusable = true;
+                }
+                // the caller rewrites the whole buffer, don't count it twice
+                if let Some(pb) = pb {
+                    pb.set_position(pb.position().saturating_sub(progressed));
+                }
+                Err(e)
+            }
+            None => Ok(()),
+        }
+    }
+}
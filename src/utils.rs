@@ -1,13 +1,114 @@
+use std::cmp::min;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::path::Path;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// zero-fill writes, large and aligned for any sector size
+const ZERO_FILL_CHUNK: u64 = 8 << 20;
+const ZERO_FILL_ALIGN: usize = 1 << 16;
+
+/// How a new plot file gets its space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prealloc {
+    Fallocate,
+    Sparse,
+    ZeroFill,
+    // fallocate, zero-fill where the file system doesn't support it
+    Auto,
+}
+
+impl FromStr for Prealloc {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Prealloc, String> {
+        match s {
+            "fallocate" => Ok(Prealloc::Fallocate),
+            "sparse" => Ok(Prealloc::Sparse),
+            "zero-fill" => Ok(Prealloc::ZeroFill),
+            "auto" => Ok(Prealloc::Auto),
+            _ => Err(format!("unknown preallocation strategy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Prealloc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Prealloc::Fallocate => "fallocate",
+            Prealloc::Sparse => "sparse",
+            Prealloc::ZeroFill => "zero-fill",
+            Prealloc::Auto => "auto",
+        })
+    }
+}
+
+/// A scratch file or directory, removed when it goes out of scope, on
+/// errors and panics as well.
+pub struct ScratchPath(pub PathBuf);
+
+impl ScratchPath {
+    // a fresh path in the temp directory, named after the test using it
+    #[cfg(test)]
+    pub fn temp(name: &str) -> ScratchPath {
+        let scratch = ScratchPath(std::env::temp_dir().join(format!("anne-plotter-{}-{}", name, std::process::id())));
+        scratch.remove();
+        scratch
+    }
+
+    fn remove(&self) {
+        let _ = std::fs::remove_file(&self.0);
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Deref for ScratchPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ScratchPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+// writes zeros over the whole file, for file systems without fallocate
+fn zero_fill(path: &Path, size_in_bytes: u64, use_direct_io: bool) -> io::Result<()> {
+    let file = if use_direct_io {
+        open_using_direct_io(path).or_else(|_| open(path))?
+    } else {
+        open(path)?
+    };
+    let chunk = min(ZERO_FILL_CHUNK, size_in_bytes) as usize;
+    let buf = vec![0u8; chunk + ZERO_FILL_ALIGN];
+    let pad = buf.as_ptr().align_offset(ZERO_FILL_ALIGN);
+    let zeros = &buf[pad..pad + chunk];
+
+    let mut offset = 0;
+    while offset < size_in_bytes {
+        let len = min(chunk as u64, size_in_bytes - offset) as usize;
+        write_vectored_at(&file, &[&zeros[..len]], offset)?;
+        offset += len as u64;
+    }
+    Ok(())
+}
 
 cfg_if::cfg_if! {
     if #[cfg(unix)] {
         #[cfg(target_os = "linux")]
         extern crate thread_priority;
-        use std::process::Command;
-        use std::process;
         use std::os::unix::fs::OpenOptionsExt;
         use fs2::FileExt;
         #[cfg(target_os = "linux")]
@@ -42,181 +143,233 @@ cfg_if::cfg_if! {
                 .read(true)
                 .open(path)
         }
-        
-
-        fn get_device_id_unix(path: &str) -> String {
 
-            let path_obj = Path::new(path);
-            let parent = path_obj.parent()
-                .unwrap_or_else(|| Path::new("/"));
-            
-            if !parent.exists() {
-                panic!("Parent directory does not exist: {:?}. Please create it first.", parent);
-            }
-            
-
-            let actual_path = parent.to_str().unwrap();
-            
-
-            let output = Command::new("df")
-                .arg("--output=source")
-                .arg(actual_path)
-                .output()
-                .expect("failed to execute 'df --output=source'");
-            
-            let source = String::from_utf8(output.stdout).expect("not utf8");
-            let lines: Vec<&str> = source.trim().split('\n').collect();
-            
-            if lines.len() >= 2 {
-                let device = lines[1].trim();
-                if !device.is_empty() {
-                    return device.to_string();
-                }
-            }
-            
-            panic!("Could not determine device for path: {} (parent: {})", path, actual_path);
-        }
-
-        fn get_sector_size_macos(path: &str) -> u64 {
-            let source = get_device_id_unix(path);
-            let output = Command::new("diskutil")
-                .arg("info")
-                .arg(&source)
-                .output()
-                .expect("failed to execute 'diskutil info'");
-            let source = String::from_utf8(output.stdout).expect("not utf8");
-            let mut sector_size: u64 = 0;
-            for line in source.split('\n') {
-                if line.trim().starts_with("Device Block Size") {
-
-                    let parts: Vec<&str> = line.split(':').collect();
-                    if parts.len() >= 2 {
-                        let value_part = parts[1].trim();
-
-                        let size_str = value_part.split_whitespace().next().unwrap_or("4096");
-                        sector_size = size_str.parse::<u64>().unwrap_or(4096);
-                        break;
+        // pwritev() the slices back to back starting at offset, retrying
+        // short writes until everything is written
+        pub fn write_vectored_at(file: &File, bufs: &[&[u8]], offset: u64) -> io::Result<()> {
+            use std::os::unix::io::AsRawFd;
+
+            let mut iovecs: Vec<libc::iovec> = bufs
+                .iter()
+                .map(|x| libc::iovec {
+                    iov_base: x.as_ptr() as *mut libc::c_void,
+                    iov_len: x.len(),
+                })
+                .collect();
+            let mut iovecs = &mut iovecs[..];
+            let mut offset = offset;
+            while !iovecs.is_empty() {
+                let written = unsafe {
+                    libc::pwritev(
+                        file.as_raw_fd(),
+                        iovecs.as_ptr(),
+                        iovecs.len() as libc::c_int,
+                        offset as libc::off_t,
+                    )
+                };
+                if written < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        continue;
                     }
+                    return Err(err);
+                }
+                if written == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "pwritev wrote 0 bytes"));
+                }
+                let mut written = written as usize;
+                offset += written as u64;
+                while !iovecs.is_empty() && written >= iovecs[0].iov_len {
+                    written -= iovecs[0].iov_len;
+                    iovecs = &mut iovecs[1..];
+                }
+                if written > 0 {
+                    iovecs[0].iov_base = unsafe { (iovecs[0].iov_base as *mut u8).add(written) } as *mut libc::c_void;
+                    iovecs[0].iov_len -= written;
                 }
             }
-            if sector_size == 0 {
-                panic!("Abort: Unable to determine disk physical sector size from diskutil info")
+            Ok(())
+        }
+
+        pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            use std::os::unix::fs::FileExt;
+
+            file.read_exact_at(buf, offset)
+        }
+
+        // evicts a file range from the page cache, so reading it back hits
+        // the disk. Dirty pages stay, the file has to be synced before.
+        pub fn drop_page_cache(file: &File, offset: u64, len: u64) -> io::Result<()> {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::unix::io::AsRawFd;
+
+                let ret = unsafe {
+                    libc::posix_fadvise(
+                        file.as_raw_fd(),
+                        offset as libc::off_t,
+                        len as libc::off_t,
+                        libc::POSIX_FADV_DONTNEED,
+                    )
+                };
+                if ret != 0 {
+                    return Err(io::Error::from_raw_os_error(ret));
+                }
             }
-            sector_size
+            #[cfg(not(target_os = "linux"))]
+            let _ = (offset, len);
+            Ok(())
         }
+        
 
-        fn get_sector_size_unix(path: &str) -> u64 {
-            let source = get_device_id_unix(path);
-            
+        /// Physical sector size of the block device holding `path`, or of `path`
+        /// itself if it is a block device. Without a block device behind it
+        /// (nfs, tmpfs, overlayfs) the file system block size is used, the
+        /// flag is set for such a guess.
+        pub fn get_sector_size(path: &str) -> io::Result<(u64, bool)> {
+            use std::os::unix::fs::FileTypeExt;
+
+            // the plot file doesn't exist before the first run
+            let path = Path::new(path);
+            let path = if path.exists() {
+                path
+            } else {
+                path.parent()
+                    .filter(|x| !x.as_os_str().is_empty())
+                    .unwrap_or_else(|| Path::new("."))
+            };
+            let metadata = std::fs::metadata(path)?;
+            let unknown = || {
+                io::Error::other(format!("couldn't determine the sector size of {}", path.display()))
+            };
 
-            let output = match Command::new("lsblk")
-                .arg(&source)
-                .arg("-o")
-                .arg("PHY-SEC")
-                .arg("-b")
-                .arg("-n")
-                .output() {
-                    Ok(output) => output,
-                    Err(_) => {
+            if metadata.file_type().is_block_device() {
+                #[cfg(target_os = "linux")]
+                {
+                    use std::os::unix::fs::MetadataExt;
 
-                        return get_sector_size_fallback(&source);
+                    let dev = metadata.rdev();
+                    if let Some(x) = sysfs_sector_size(Path::new("/sys"), libc::major(dev), libc::minor(dev))
+                        .or_else(|| ioctl_sector_size(path))
+                    {
+                        return Ok((x, false));
                     }
-                };
+                }
+                // the block size of the file system the device node is on
+                // (devtmpfs) says nothing about the device
+                return Err(unknown());
+            }
+
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::unix::fs::MetadataExt;
 
-            let sector_size_str = String::from_utf8(output.stdout).expect("not utf8");
-            let sector_size = sector_size_str.trim();
-            
-            if sector_size.is_empty() {
-                return get_sector_size_fallback(&source);
+                let dev = metadata.dev();
+                if let Some(x) = sysfs_sector_size(Path::new("/sys"), libc::major(dev), libc::minor(dev)) {
+                    return Ok((x, false));
+                }
+                // only anonymous devices (nfs, tmpfs, overlayfs, btrfs) have
+                // no block device to ask
+                if libc::major(dev) != 0 {
+                    return Err(unknown());
+                }
             }
-            
-            sector_size.parse::<u64>().unwrap_or_else(|_| {
-                println!("Warning: Failed to parse sector size '{}', defaulting to 512", sector_size);
-                4096
-            })
+            statfs_block_size(path).map(|x| (x, true)).ok_or_else(unknown)
         }
 
-        fn get_sector_size_fallback(device: &str) -> u64 {
-            match Command::new("blockdev")
-                .arg("--getpbsz")
-                .arg(device)
-                .output() {
-                    Ok(output) => {
-                        let size_str = String::from_utf8(output.stdout).expect("not utf8");
-                        size_str.trim().parse::<u64>().unwrap_or(4096)
-                    }
-                    Err(_) => {
-                        println!("Warning: Could not determine sector size, defaulting to 4096");
-                        4096
-                    }
+        // asks the block device itself, for a missing or incomplete /sys
+        #[cfg(target_os = "linux")]
+        fn ioctl_sector_size(path: &Path) -> Option<u64> {
+            use std::os::unix::io::AsRawFd;
+
+            let file = open_r(path).ok()?;
+            [libc::BLKPBSZGET, libc::BLKSSZGET].iter().find_map(|&request| {
+                let mut size: libc::c_int = 0;
+                if unsafe { libc::ioctl(file.as_raw_fd(), request, &mut size) } != 0 {
+                    return None;
                 }
+                Some(size as u64).filter(|&x| x > 0)
+            })
         }
 
-        pub fn get_sector_size(path: &str) -> u64 {
-            if cfg!(target_os = "macos") {
-                get_sector_size_macos(path)
-            } else {
-                get_sector_size_unix(path)
-            }
+        // reads the sector size of a block device from
+        // <sys>/dev/block/<major>:<minor>/queue. Partitions have no queue of
+        // their own and use the one of their disk, stacked devices (device-mapper,
+        // md) use at least the largest sector size of the devices below them.
+        #[cfg(target_os = "linux")]
+        fn sysfs_sector_size(sys: &Path, major: u32, minor: u32) -> Option<u64> {
+            let dir = std::fs::canonicalize(sys.join(format!("dev/block/{}:{}", major, minor))).ok()?;
+            sysfs_device_sector_size(&dir)
         }
 
-        pub fn preallocate(file: &Path, size_in_bytes: u64, use_direct_io: bool) {
-            if use_direct_io {
+        #[cfg(target_os = "linux")]
+        fn sysfs_device_sector_size(dir: &Path) -> Option<u64> {
+            let queue_sector_size = |dir: &Path| {
+                ["physical_block_size", "logical_block_size"].iter().find_map(|x| {
+                    std::fs::read_to_string(dir.join("queue").join(x))
+                        .ok()?
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|&x| x > 0)
+                })
+            };
 
-                preallocate_direct_io(file, size_in_bytes)
+            let own = if dir.join("partition").exists() {
+                dir.parent().and_then(queue_sector_size)
             } else {
-                preallocate_normal(file, size_in_bytes)
-            }
+                queue_sector_size(dir)
+            };
+            let below = std::fs::read_dir(dir.join("slaves"))
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(|x| std::fs::canonicalize(x.path()).ok())
+                .filter_map(|x| sysfs_device_sector_size(&x))
+                .max();
+            own.into_iter().chain(below).max()
         }
 
-        fn preallocate_normal(file: &Path, size_in_bytes: u64) {
-            let file = open(&file).unwrap();
-            match file.allocate(size_in_bytes) {
-                Err(errno) => {
-                    eprintln!("\n\nError: couldn't preallocate space for file. {}\n\
-                            Probable causes are:\n \
-                            * fallocate() is only supported on ext4 filesystems.\n \
-                            * Insufficient space.\n", errno);
-                    process::exit(1);
-                }
-                Ok(_) => (),
+        // the file system block size can be far larger than a sector (nfs, zfs),
+        // it is capped at 4 KiB which keeps direct i/o aligned on any device
+        fn statfs_block_size(path: &Path) -> Option<u64> {
+            use std::ffi::CString;
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+            let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+            if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+                return None;
             }
+            Some((stat.f_bsize as u64).min(4096)).filter(|&x| x > 0)
         }
 
-        fn preallocate_direct_io(file: &Path, size_in_bytes: u64) {
-
-            let sector_size = get_sector_size(file.to_str().unwrap_or("/"));
-            let aligned_size = ((size_in_bytes + sector_size - 1) / sector_size) * sector_size;
-            
-
-            let file_result = open_using_direct_io(&file);
-            
-            match file_result {
-                Ok(file) => {
-
-                    use std::os::unix::io::AsRawFd;
-                    use libc::{ftruncate, c_int};
-                    
-                    let fd = file.as_raw_fd();
-                    
-                    unsafe {
-                        if ftruncate(fd as c_int, aligned_size as i64) != 0 {
-                            let err = io::Error::last_os_error();
-                            println!("\n\nError: couldn't allocate space for O_DIRECT file. {}\n\
-                                      Probable causes are:\n \
-                                      * O_DIRECT requires aligned sizes\n \
-                                      * Filesystem doesn't support O_DIRECT properly\n \
-                                      * Try running without direct I/O flag\n", err);
-                            process::exit(1);
+        /// Preallocates a new plot file, returns the strategy that was used.
+        pub fn preallocate(
+            file: &Path,
+            size_in_bytes: u64,
+            use_direct_io: bool,
+            strategy: Prealloc,
+        ) -> io::Result<Prealloc> {
+            match strategy {
+                Prealloc::Fallocate => open(file)?.allocate(size_in_bytes)?,
+                Prealloc::Sparse => open(file)?.set_len(size_in_bytes)?,
+                Prealloc::ZeroFill => zero_fill(file, size_in_bytes, use_direct_io)?,
+                Prealloc::Auto => {
+                    return match open(file)?.allocate(size_in_bytes) {
+                        Ok(_) => Ok(Prealloc::Fallocate),
+                        // FUSE, some NTFS and network mounts
+                        Err(e) if e.raw_os_error().is_some_and(|x| {
+                            [libc::EOPNOTSUPP, libc::ENOTSUP, libc::ENOSYS, libc::EINVAL].contains(&x)
+                        }) => {
+                            zero_fill(file, size_in_bytes, use_direct_io)?;
+                            Ok(Prealloc::ZeroFill)
                         }
-                    }
-                }
-                Err(e) => {
-
-                    eprintln!("\nWarning: O_DIRECT open failed: {}. Using normal I/O.", e);
-                    preallocate_normal(file, size_in_bytes);
+                        Err(e) => Err(e),
+                    };
                 }
             }
+            Ok(strategy)
         }
 
         pub fn free_disk_space(path: &str) -> u64 {
@@ -224,6 +377,70 @@ cfg_if::cfg_if! {
             fs2::available_space(Path::new(&path)).unwrap().saturating_sub(2097152)
         }
 
+        // whether the effective capabilities of the process include `cap`
+        #[cfg(target_os = "linux")]
+        fn has_capability(cap: u32) -> bool {
+            std::fs::read_to_string("/proc/self/status")
+                .ok()
+                .and_then(|x| {
+                    let caps = x.lines().find_map(|x| x.strip_prefix("CapEff:"))?;
+                    u64::from_str_radix(caps.trim(), 16).ok()
+                })
+                .is_some_and(|x| x & (1 << cap) != 0)
+        }
+
+        /// Raises the soft RLIMIT_MEMLOCK as far as permitted. Returns the
+        /// bytes that can be locked in RAM, None if there is no limit.
+        pub fn raise_memlock_limit() -> io::Result<Option<u64>> {
+            // CAP_IPC_LOCK locks memory regardless of the limit
+            #[cfg(target_os = "linux")]
+            if has_capability(14) {
+                return Ok(None);
+            }
+            let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // privileged processes may lift the hard limit as well
+            let unlimited = libc::rlimit {
+                rlim_cur: libc::RLIM_INFINITY,
+                rlim_max: libc::RLIM_INFINITY,
+            };
+            if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &unlimited) } == 0 {
+                return Ok(None);
+            }
+            limit.rlim_cur = limit.rlim_max;
+            if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // rlim_t is 32 bits wide on some targets
+            #[allow(clippy::unnecessary_cast)]
+            Ok(if limit.rlim_cur == libc::RLIM_INFINITY {
+                None
+            } else {
+                Some(limit.rlim_cur as u64)
+            })
+        }
+
+        /// Restricts the current thread to `cpus`.
+        #[cfg(target_os = "linux")]
+        pub fn set_thread_affinity(cpus: &[usize]) -> io::Result<()> {
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            for &cpu in cpus {
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            if unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        // macOS has no affinity masks, only hints
+        #[cfg(not(target_os = "linux"))]
+        pub fn set_thread_affinity(_cpus: &[usize]) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+
     } else {
         use std::ffi::CString;
         use std::ptr::null_mut;
@@ -238,7 +455,7 @@ cfg_if::cfg_if! {
         use winapi::um::handleapi::CloseHandle;
         use winapi::um::processthreadsapi::{SetThreadIdealProcessor,GetCurrentThread,OpenProcessToken,GetCurrentProcess,SetPriorityClass};
         use winapi::um::securitybaseapi::AdjustTokenPrivileges;
-        use winapi::um::winbase::LookupPrivilegeValueW;
+        use winapi::um::winbase::{LookupPrivilegeValueW,SetThreadAffinityMask};
         use winapi::um::winnt::{LUID,TOKEN_ADJUST_PRIVILEGES,TOKEN_PRIVILEGES,LUID_AND_ATTRIBUTES,SE_PRIVILEGE_ENABLED,SE_MANAGE_VOLUME_NAME};
 
         const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
@@ -247,6 +464,7 @@ cfg_if::cfg_if! {
 
         pub fn open_using_direct_io<P: AsRef<Path>>(path: P) -> io::Result<File> {
             OpenOptions::new()
+                .read(true)
                 .write(true)
                 .create(true)
                 .custom_flags(FILE_FLAG_NO_BUFFERING)
@@ -267,18 +485,75 @@ cfg_if::cfg_if! {
                 .open(path)
         }
 
-        pub fn preallocate(file: &Path, size_in_bytes: u64, use_direct_io: bool) {
+        pub fn write_vectored_at(file: &File, bufs: &[&[u8]], offset: u64) -> io::Result<()> {
+            use std::os::windows::fs::FileExt;
+
+            let mut offset = offset;
+            for buf in bufs {
+                let mut buf = *buf;
+                while !buf.is_empty() {
+                    let written = file.seek_write(buf, offset)?;
+                    if written == 0 {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "seek_write wrote 0 bytes"));
+                    }
+                    buf = &buf[written..];
+                    offset += written as u64;
+                }
+            }
+            Ok(())
+        }
+
+        // files opened without FILE_FLAG_NO_BUFFERING are written through
+        pub fn drop_page_cache(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+            Ok(())
+        }
+
+        pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            use std::os::windows::fs::FileExt;
+
+            let mut buf = buf;
+            let mut offset = offset;
+            while !buf.is_empty() {
+                let read = file.seek_read(buf, offset)?;
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+                }
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Ok(())
+        }
+
+        /// Preallocates a new plot file, returns the strategy that was used.
+        /// fallocate is emulated with SetFileValidData, which needs admin rights.
+        pub fn preallocate(
+            path: &Path,
+            size_in_bytes: u64,
+            use_direct_io: bool,
+            strategy: Prealloc,
+        ) -> io::Result<Prealloc> {
+            match strategy {
+                Prealloc::Sparse => {
+                    open(path)?.set_len(size_in_bytes)?;
+                    return Ok(strategy);
+                }
+                Prealloc::ZeroFill => {
+                    zero_fill(path, size_in_bytes, use_direct_io)?;
+                    return Ok(strategy);
+                }
+                Prealloc::Fallocate | Prealloc::Auto => (),
+            }
+
             let mut result = true;
             result &= obtain_priviledge();
 
             let file = if use_direct_io {
-                open_using_direct_io(&file)
+                open_using_direct_io(path)?
             } else {
-                open(&file)
+                open(path)?
             };
-            let file = file.unwrap();
 
-            file.set_len(size_in_bytes).unwrap();
+            file.set_len(size_in_bytes)?;
 
             if result {
                 let handle = file.as_raw_handle();
@@ -289,9 +564,13 @@ cfg_if::cfg_if! {
             }
 
             if !result {
-                println!("FAILED, administrative rights missing");
-                print!("Slow file pre-allocation...");
+                if strategy == Prealloc::Fallocate {
+                    return Err(io::Error::other("SetFileValidData failed, administrative rights missing"));
+                }
+                zero_fill(path, size_in_bytes, use_direct_io)?;
+                return Ok(Prealloc::ZeroFill);
             }
+            Ok(Prealloc::Fallocate)
         }
 
         pub fn obtain_priviledge() -> bool {
@@ -334,7 +613,7 @@ cfg_if::cfg_if! {
             result
         }
 
-        pub fn get_sector_size(path: &str) -> u64 {
+        pub fn get_sector_size(path: &str) -> io::Result<(u64, bool)> {
             let path_encoded = Path::new(path);
             let parent_path_encoded = CString::new(path_encoded.to_str().unwrap()).unwrap();
             let mut sectors_per_cluster  = 0u32;
@@ -350,9 +629,9 @@ cfg_if::cfg_if! {
                     &mut total_number_of_cluster
                 )
             } == 0  {
-                panic!("get sector size, filename={}",path);
+                return Err(io::Error::last_os_error());
             };
-            u64::from(bytes_per_sector)
+            Ok((u64::from(bytes_per_sector), false))
         }
 
         pub fn set_thread_ideal_processor(id: usize){
@@ -372,5 +651,83 @@ cfg_if::cfg_if! {
         pub fn free_disk_space(path: &str) -> u64 {
             fs2::available_space(Path::new(&path)).unwrap()
         }
+
+        // the mask covers the processor group of the thread, up to 64 cpus
+        pub fn set_thread_affinity(cpus: &[usize]) -> io::Result<()> {
+            let mask = cpus.iter().filter(|x| **x < usize::BITS as usize).fold(0usize, |mask, x| mask | 1 << x);
+            if unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        // buffers aren't locked on windows
+        pub fn raise_memlock_limit() -> io::Result<Option<u64>> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn sysfs_sector_size_walk() {
+        let sys = ScratchPath::temp("sysfs");
+        let sda = sys.join("devices/pci0/block/sda");
+        let dm = sys.join("devices/virtual/block/dm-0");
+        let loop0 = sys.join("devices/virtual/block/loop0");
+        for dir in [
+            sda.join("queue"),
+            sda.join("sda1"),
+            dm.join("queue"),
+            dm.join("slaves"),
+            loop0.join("queue"),
+            sys.join("dev/block"),
+        ] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(sda.join("queue/physical_block_size"), "4096\n").unwrap();
+        fs::write(sda.join("queue/logical_block_size"), "512\n").unwrap();
+        fs::write(sda.join("sda1/partition"), "1\n").unwrap();
+        // dm-crypt on top of the partition reporting 512 byte sectors
+        fs::write(dm.join("queue/physical_block_size"), "512\n").unwrap();
+        symlink(sda.join("sda1"), dm.join("slaves/sda1")).unwrap();
+        fs::write(loop0.join("queue/logical_block_size"), "512\n").unwrap();
+        symlink(&sda, sys.join("dev/block/8:0")).unwrap();
+        symlink(sda.join("sda1"), sys.join("dev/block/8:1")).unwrap();
+        symlink(&dm, sys.join("dev/block/254:0")).unwrap();
+        symlink(&loop0, sys.join("dev/block/7:0")).unwrap();
+
+        assert_eq!(sysfs_sector_size(&sys, 8, 0), Some(4096));
+        assert_eq!(sysfs_sector_size(&sys, 8, 1), Some(4096));
+        assert_eq!(sysfs_sector_size(&sys, 254, 0), Some(4096));
+        assert_eq!(sysfs_sector_size(&sys, 7, 0), Some(512));
+        // overlayfs, tmpfs, nfs: anonymous device without sysfs entry
+        assert_eq!(sysfs_sector_size(&sys, 0, 42), None);
+    }
+
+    #[test]
+    fn preallocation_strategies() {
+        use std::os::unix::fs::MetadataExt;
+
+        let size = 4u64 << 20;
+        for (strategy, allocated) in [
+            (Prealloc::Sparse, false),
+            (Prealloc::Fallocate, true),
+            (Prealloc::ZeroFill, true),
+            (Prealloc::Auto, true),
+        ] {
+            let path = ScratchPath::temp(&format!("prealloc-{}", strategy));
+            let used = preallocate(&path, size, true, strategy).unwrap();
+            assert_ne!(used, Prealloc::Auto);
+            let metadata = fs::metadata(&path).unwrap();
+            assert_eq!(metadata.len(), size);
+            assert_eq!(metadata.blocks() * 512 >= size, allocated, "{}", strategy);
+            assert!(fs::read(&path).unwrap().iter().all(|&x| x == 0));
+        }
     }
 }
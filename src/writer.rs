@@ -1,97 +1,241 @@
-use crate::plotter::{PlotterTask, NONCE_SIZE, SCOOP_SIZE};
+use crate::plotter::{PlotterTask, NONCE_SIZE, NUM_SCOOPS, SCOOP_SIZE};
 use crate::buffer::PageAlignedByteBuffer;
-use crate::utils::{open, open_r, open_using_direct_io};
+use crate::device::PlotDevice;
+use crate::throttle::RateLimiter;
+#[cfg(target_os = "linux")]
+use crate::uring::UringWriter;
+use crate::utils::{
+    drop_page_cache, open, open_r, open_using_direct_io, read_exact_at, write_vectored_at, ScratchPath,
+};
 use crossbeam_channel::{Receiver, Sender};
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write, Error, ErrorKind};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use indicatif::ProgressBar;
+use memmap2::{MmapMut, MmapOptions};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::str::FromStr;
 
-const TASK_SIZE: u64 = 16384;
+// iovecs per vectored write, stays below IOV_MAX
+const MAX_IOVECS: usize = 1024;
+// rewrites of a buffer that failed write verification before giving up
+const VERIFY_RETRIES: u32 = 3;
+// size of the scratch plot written by the writer benchmark, 128 MiB in 4 buffers
+const BENCH_NONCES: u64 = 512;
+const BENCH_BUFFER_NONCES: u64 = 128;
 
 pub fn create_writer_thread(
     task: Arc<PlotterTask>,
+    mut target: PlotTarget,
     mut nonces_written: u64,
+    sector_size: u64,
     pb: Option<ProgressBar>,
-    rx_buffers_to_writer: Receiver<PageAlignedByteBuffer>,
-    tx_empty_buffers: Sender<PageAlignedByteBuffer>,
-) -> impl FnOnce() {
+    rx_buffers_to_writer: Receiver<(u64, PageAlignedByteBuffer)>,
+    tx_empty_buffers: Vec<Sender<PageAlignedByteBuffer>>,
+) -> impl FnOnce() -> Result<Duration, WriteAbort> {
     move || {
-        for buffer in rx_buffers_to_writer {
+        #[cfg(target_os = "linux")]
+        let mut uring = if task.io_uring && task.mmap.is_none() && !task.benchmark {
+            match UringWriter::new(task.queue_depth) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    if !task.quiet {
+                        eprintln!("io_uring unavailable: {}. Using blocking writes.", e);
+                    }
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // opened with the first buffer and kept for the whole run
+        let mut plot_file: Option<PlotFile> = None;
+        let mut verifier: Option<WriteVerifier> = None;
+        let mut sync_policy = SyncPolicy::new(task.sync_interval);
+        let retry = RetryPolicy::new(task.write_retries, task.retry_delay);
+        // nonces the resume marker currently covers
+        let mut committed = nonces_written;
+
+        // stops the writer, the resume marker is moved to the last buffer
+        // written in full if the disk still takes it
+        macro_rules! abort {
+            ($error:expr) => {{
+                let error = $error;
+                if let Some(pb) = &pb {
+                    pb.abandon_with_message("Writer aborted.");
+                }
+                if let Some(data) = plot_file.as_mut() {
+                    let mut commit = PlotCommit {
+                        data,
+                        target: &mut target,
+                        plot_nonces: task.nonces,
+                    };
+                    if commit_progress(&mut commit, nonces_written).is_ok() {
+                        committed = nonces_written;
+                    }
+                }
+                return Err(WriteAbort {
+                    error,
+                    resume_from: committed,
+                });
+            }};
+        }
+
+        // time spent waiting for the other side of the buffer pipeline
+        let mut stall = Duration::ZERO;
+        // buffers of worker groups that got ahead of the plot order
+        let mut pending = BTreeMap::new();
+        'buffers: loop {
+            let wait = Instant::now();
+            let buffer = loop {
+                match pending.remove(&nonces_written) {
+                    Some(x) => break x,
+                    None => match rx_buffers_to_writer.recv() {
+                        Ok((offset, x)) => {
+                            pending.insert(offset, x);
+                        }
+                        Err(_) => break 'buffers,
+                    },
+                }
+            };
+            stall += wait.elapsed();
+
             let mut_bs = &buffer.get_buffer();
             let bs = mut_bs.lock().unwrap();
             let buffer_size = (*bs).len() as u64;
             let nonces_to_write = min(buffer_size / NONCE_SIZE, task.nonces - nonces_written);
 
-            let filename = Path::new(&task.output_path).join(format!(
-                "{}_{}_{}",
-                task.numeric_id, task.start_nonce, task.nonces
-            ));
             if !task.benchmark {
-                let file_result = if task.direct_io {
-                    open_using_direct_io(&filename)
-                } else {
-                    open(&filename)
-                };
-                let mut file = match file_result {
-                    Ok(f) => f,
-                    Err(e) if e.raw_os_error() == Some(libc::EINVAL as i32) => {
-                      //  eprintln!("Warning: O_DIRECT open failed: {}. Using normal I/O.", e);
-                        match open(&filename) {
-                            Ok(f) => f,
-                            Err(e2) => {
-                                eprintln!("Error: Normal open also failed: {}", e2);
-
-                                tx_empty_buffers.send(buffer).unwrap();
-                                continue;
+                if plot_file.is_none() {
+                    let opened = retry.run("File open", || match task.mmap {
+                        Some(advice) => PlotFile::open_mmap(
+                            target.path(),
+                            target.base_offset(),
+                            task.nonces * NONCE_SIZE,
+                            advice,
+                        ),
+                        None => PlotFile::open(
+                            target.path(),
+                            task.direct_io,
+                            target.base_offset(),
+                            sector_size,
+                        ),
+                    });
+                    match opened {
+                        Ok(mut f) => {
+                            if task.direct_io && !f.direct_io && !task.quiet {
+                                eprintln!("Warning: direct i/o not supported for this file. Using normal I/O.");
+                            }
+                            if task.max_write_rate > 0 || task.write_rate_file.is_some() {
+                                f.limiter = Some(RateLimiter::with_rate_file(
+                                    task.max_write_rate,
+                                    task.write_rate_file.as_deref().map(Path::new),
+                                ));
+                            }
+                            f.retry = retry;
+                            plot_file = Some(f);
+                            if task.verify_scoops > 0 {
+                                match WriteVerifier::open(target.path(), sector_size, task.verify_scoops) {
+                                    Ok(x) => {
+                                        if !x.reads_disk() && !task.quiet {
+                                            eprintln!("Warning: no direct i/o for write verification, written data is only checked against the page cache.");
+                                        }
+                                        verifier = Some(x);
+                                    }
+                                    Err(e) => abort!(Error::new(
+                                        e.kind(),
+                                        format!("couldn't open plot file for write verification: {}", e)
+                                    )),
+                                }
                             }
                         }
+                        Err(e) => abort!(Error::new(e.kind(), format!("file open failed: {}", e))),
                     }
-                    Err(e) => {
-                        eprintln!("Error: File open failed: {}", e);
+                }
+                let file = plot_file.as_mut().unwrap();
+                if let (Some(limiter), Some(pb)) = (&file.limiter, &pb) {
+                    pb.set_message(limiter.describe());
+                }
 
-                        tx_empty_buffers.send(buffer).unwrap();
-                        continue;
-                    }
+                // unaligned scoop regions share sectors, they are written
+                // one after the other by the blocking path
+                #[cfg(target_os = "linux")]
+                let written_by_uring = match uring.as_mut() {
+                    Some(ring) if file.is_aligned(task.nonces, nonces_written, nonces_to_write) => match ring.write_buffer(
+                        file,
+                        &bs,
+                        task.nonces,
+                        nonces_written,
+                        nonces_to_write,
+                        &pb,
+                    ) {
+                        Ok(_) => true,
+                        // the buffer is rewritten by blocking writes, later ones
+                        // too if the ring can't write this file
+                        Err(e) => {
+                            let usable = ring.usable();
+                            if !task.quiet {
+                                eprintln!(
+                                    "io_uring write failed: {}. Falling back to blocking writes{}.",
+                                    e,
+                                    if usable { " for this buffer" } else { "" }
+                                );
+                            }
+                            if !usable {
+                                uring = None;
+                            }
+                            false
+                        }
+                    },
+                    _ => false,
                 };
+                #[cfg(not(target_os = "linux"))]
+                let written_by_uring = false;
 
-                for scoop in 0..4096 {
-                    let mut seek_addr = scoop * task.nonces as u64 * SCOOP_SIZE;
-                    seek_addr += nonces_written as u64 * SCOOP_SIZE;
-
-                    if let Err(e) = file.seek(SeekFrom::Start(seek_addr)) {
-                        eprintln!("Seek failed for scoop {}: {}. Skipping scoop.", scoop, e);
-                        continue;
-                    }
+                if !written_by_uring
+                    && let Err(e) = file.write_buffer(&bs, task.nonces, nonces_written, nonces_to_write, &pb)
+                {
+                    abort!(Error::new(e.kind(), format!("write failed: {}", e)));
+                }
 
-                    let mut local_addr = scoop * buffer_size / NONCE_SIZE * SCOOP_SIZE;
-                    for _ in 0..nonces_to_write / TASK_SIZE {
-                        if let Err(e) = file.write_all(
-                            &bs[local_addr as usize
-                                ..(local_addr + TASK_SIZE * SCOOP_SIZE) as usize],
-                        ) {
-                            eprintln!("Write failed in scoop {}: {}. Skipping chunk.", scoop, e);
-                            break;
+                // the resume marker only moves past verified buffers
+                if let Some(verifier) = verifier.as_mut() {
+                    let start_addr = file.base_offset + nonces_written * SCOOP_SIZE;
+                    let mut attempt = 0;
+                    while let Err(e) =
+                        verifier.verify(&bs, task.nonces, start_addr, nonces_to_write, nonces_written)
+                    {
+                        attempt += 1;
+                        if attempt > VERIFY_RETRIES {
+                            abort!(Error::new(e.kind(), format!("write verification failed: {}", e)));
                         }
-                        local_addr += TASK_SIZE * SCOOP_SIZE;
-                    }
-
-                    if nonces_to_write % TASK_SIZE > 0 {
-                        if let Err(e) = file.write_all(
-                            &bs[local_addr as usize
-                                ..(local_addr + (nonces_to_write % TASK_SIZE) * SCOOP_SIZE)
-                                as usize],
-                        ) {
-                            eprintln!("Remainder write failed in scoop {}: {}. Skipping.", scoop, e);
+                        eprintln!(
+                            "Warning: write verification failed: {}. Rewriting buffer ({}/{})...",
+                            e, attempt, VERIFY_RETRIES
+                        );
+                        if let Err(e) = file.write_buffer(&bs, task.nonces, nonces_written, nonces_to_write, &None) {
+                            abort!(Error::new(e.kind(), format!("write failed: {}", e)));
                         }
                     }
+                }
 
-                    if (scoop + 1) % 128 == 0 {
-                        if let Some(pb_ref) = &pb {
-                            pb_ref.inc(nonces_to_write * SCOOP_SIZE * 128u64);
-                        }
-                    }
+                let mut commit = PlotCommit {
+                    data: file,
+                    target: &mut target,
+                    plot_nonces: task.nonces,
+                };
+                match sync_policy.buffer_written(&mut commit, nonces_written + nonces_to_write, task.nonces) {
+                    Ok(true) => committed = nonces_written + nonces_to_write,
+                    Ok(false) => (),
+                    Err(e) => abort!(Error::new(e.kind(), format!("couldn't write resume info: {}", e))),
                 }
             }
             nonces_written += nonces_to_write;
@@ -100,20 +244,584 @@ pub fn create_writer_thread(
                 if let Some(pb_ref) = &pb {
                     pb_ref.finish_with_message("Writer done.");
                 }
-                tx_empty_buffers.send(buffer).unwrap();
+                // the hasher is done and gone, the buffer isn't needed anymore
                 break;
             }
 
-            if !task.benchmark {
-                if write_resume_info(&filename, nonces_written).is_err() {
-                    println!("Error: couldn't write resume info");
+            // a worker group stops once all nonces are claimed, its buffers
+            // aren't needed anymore then
+            let _ = tx_empty_buffers[buffer.group].send(buffer);
+        }
+        Ok(stall)
+    }
+}
+
+/// A plot the writer gave up on, it can be resumed from `resume_from`.
+#[derive(Debug)]
+pub struct WriteAbort {
+    pub error: Error,
+    pub resume_from: u64,
+}
+
+impl fmt::Display for WriteAbort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}. Plotting aborted, it can be resumed from nonce offset {}.",
+            self.error, self.resume_from
+        )
+    }
+}
+
+/// How often a failed write is repeated before the writer gives up. The
+/// delay between attempts doubles each time. Writes are positioned, so
+/// repeating one is safe.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, delay: Duration) -> RetryPolicy {
+        RetryPolicy { retries, delay }
+    }
+
+    pub fn run<T>(&self, what: &str, mut f: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    eprintln!("Warning: {} failed: {}. Retrying ({}/{})...", what, e, attempt, self.retries);
+                    thread::sleep(self.delay * (1 << min(attempt - 1, 16)));
                 }
+                x => return x,
+            }
+        }
+    }
+}
+
+/// Where a plot is written to: its own plot file or a slot of a plot device.
+pub enum PlotTarget {
+    File(PathBuf),
+    Device(PlotDevice, usize),
+}
+
+impl PlotTarget {
+    fn path(&self) -> &Path {
+        match self {
+            PlotTarget::File(path) => path,
+            PlotTarget::Device(device, _) => &device.path,
+        }
+    }
+
+    fn base_offset(&self) -> u64 {
+        match self {
+            PlotTarget::File(_) => 0,
+            PlotTarget::Device(device, slot) => device.entries[*slot].offset,
+        }
+    }
+
+    // the resume marker of a plot file sits in its last nonce and is
+    // overwritten by the last buffer, the device index keeps it for good.
+    // Both are written through the open plot handle.
+    fn write_resume_info(&mut self, file: &mut PlotFile, nonces_written: u64, plot_nonces: u64) -> Result<(), Error> {
+        match self {
+            PlotTarget::File(_) if nonces_written == plot_nonces => Ok(()),
+            PlotTarget::File(_) => file.write_at(
+                &resume_marker(nonces_written),
+                file.base_offset + plot_nonces * NONCE_SIZE - 8,
+            ),
+            PlotTarget::Device(device, slot) => {
+                device.entries[*slot].progress = nonces_written;
+                file.write_at(&device.index(), 0)
             }
-            tx_empty_buffers.send(buffer).unwrap();
         }
     }
 }
 
+/// The steps of advancing the resume marker, kept apart from the writer so
+/// their order can be tested against a disk that loses unflushed writes.
+pub trait Commit {
+    fn sync_data(&mut self) -> Result<(), Error>;
+    fn write_marker(&mut self, nonces_written: u64) -> Result<(), Error>;
+    fn sync_marker(&mut self) -> Result<(), Error>;
+}
+
+// the marker lives in the plot file or on the plot device and is written
+// through the handle of the data
+struct PlotCommit<'a> {
+    data: &'a mut PlotFile,
+    target: &'a mut PlotTarget,
+    plot_nonces: u64,
+}
+
+impl Commit for PlotCommit<'_> {
+    fn sync_data(&mut self) -> Result<(), Error> {
+        self.data.sync()
+    }
+
+    fn write_marker(&mut self, nonces_written: u64) -> Result<(), Error> {
+        self.target.write_resume_info(self.data, nonces_written, self.plot_nonces)
+    }
+
+    // the marker is written through the file handle, never the mapping
+    fn sync_marker(&mut self) -> Result<(), Error> {
+        self.data.file.sync_data()
+    }
+}
+
+/// Decides when the resume marker is advanced. Every `interval` buffers and
+/// after the last one, the written data is flushed, then the marker is
+/// written and flushed, so the marker never claims data lost on power loss.
+/// An interval of 0 advances the marker after every buffer without flushing.
+pub struct SyncPolicy {
+    interval: u64,
+    unsynced: u64,
+}
+
+impl SyncPolicy {
+    pub fn new(interval: u64) -> SyncPolicy {
+        SyncPolicy {
+            interval,
+            unsynced: 0,
+        }
+    }
+
+    /// Called after each written buffer, returns whether the marker moved.
+    pub fn buffer_written(
+        &mut self,
+        commit: &mut impl Commit,
+        nonces_written: u64,
+        plot_nonces: u64,
+    ) -> Result<bool, Error> {
+        if self.interval == 0 {
+            commit.write_marker(nonces_written)?;
+            return Ok(true);
+        }
+        self.unsynced += 1;
+        if self.unsynced < self.interval && nonces_written < plot_nonces {
+            return Ok(false);
+        }
+        commit_progress(commit, nonces_written)?;
+        self.unsynced = 0;
+        Ok(true)
+    }
+}
+
+/// Flushes the data, then writes and flushes the resume marker.
+pub fn commit_progress(commit: &mut impl Commit, nonces_written: u64) -> Result<(), Error> {
+    commit.sync_data()?;
+    commit.write_marker(nonces_written)?;
+    commit.sync_marker()
+}
+
+/// Access pattern the kernel is told about for a memory-mapped plot file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmapAdvice {
+    Normal,
+    // the scoop regions of a buffer are scattered over the whole plot
+    Random,
+    Sequential,
+}
+
+impl FromStr for MmapAdvice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MmapAdvice, String> {
+        match s {
+            "normal" => Ok(MmapAdvice::Normal),
+            "random" => Ok(MmapAdvice::Random),
+            "sequential" => Ok(MmapAdvice::Sequential),
+            _ => Err(format!("unknown mmap advice '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for MmapAdvice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MmapAdvice::Normal => "normal",
+            MmapAdvice::Random => "random",
+            MmapAdvice::Sequential => "sequential",
+        })
+    }
+}
+
+/// Handle to a plot file (or plot device) that is kept open for the whole
+/// run. Whether direct i/o works for the file is probed once on open. All
+/// writes are shifted by `base_offset`, the start of the plot on a device.
+/// A memory-mapped plot file is written by copying into the mapping.
+pub struct PlotFile {
+    pub file: File,
+    pub direct_io: bool,
+    pub base_offset: u64,
+    // direct i/o writes whole sectors, 1 without direct i/o
+    sector_size: u64,
+    // staging area for scoop regions which don't start or end on a sector
+    // boundary, aligned to the sector size on use
+    bounce: Vec<u8>,
+    // write rate limit of the disk the file is on
+    pub limiter: Option<RateLimiter>,
+    // the plot, from base_offset on, mapped into memory
+    map: Option<MmapMut>,
+    // applies to every single write
+    pub retry: RetryPolicy,
+}
+
+impl PlotFile {
+    pub fn open(
+        path: &Path,
+        direct_io: bool,
+        base_offset: u64,
+        sector_size: u64,
+    ) -> Result<PlotFile, Error> {
+        if direct_io {
+            match open_using_direct_io(path) {
+                Ok(file) => {
+                    return Ok(PlotFile {
+                        file,
+                        direct_io,
+                        base_offset,
+                        sector_size: max(sector_size, 1),
+                        bounce: Vec::new(),
+                        limiter: None,
+                        map: None,
+                        retry: RetryPolicy::default(),
+                    })
+                }
+                Err(e) if e.raw_os_error() != Some(libc::EINVAL) => return Err(e),
+                // O_DIRECT isn't supported by the filesystem
+                Err(_) => (),
+            }
+        }
+        Ok(PlotFile {
+            file: open(path)?,
+            direct_io: false,
+            base_offset,
+            sector_size: 1,
+            bounce: Vec::new(),
+            limiter: None,
+            map: None,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Maps the `len` bytes of the plot at `base_offset` into memory. The
+    /// file has to be preallocated, pages behind its end can't be written.
+    pub fn open_mmap(
+        path: &Path,
+        base_offset: u64,
+        len: u64,
+        advice: MmapAdvice,
+    ) -> Result<PlotFile, Error> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        // block devices report no length in their metadata
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < base_offset + len {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "plot file smaller than the plot, can't map it",
+            ));
+        }
+        // 32-bit targets can't map plots of 4 GiB and more
+        let len = usize::try_from(len).map_err(|_| {
+            Error::new(ErrorKind::InvalidInput, "plot too large to map into the address space")
+        })?;
+        // the plot file is only ever resized before plotting
+        let map = unsafe {
+            MmapOptions::new()
+                .offset(base_offset)
+                .len(len)
+                .map_mut(&file)?
+        };
+        #[cfg(unix)]
+        map.advise(match advice {
+            MmapAdvice::Normal => memmap2::Advice::Normal,
+            MmapAdvice::Random => memmap2::Advice::Random,
+            MmapAdvice::Sequential => memmap2::Advice::Sequential,
+        })?;
+        #[cfg(not(unix))]
+        let _ = advice;
+
+        Ok(PlotFile {
+            file,
+            direct_io: false,
+            base_offset,
+            sector_size: 1,
+            bounce: Vec::new(),
+            limiter: None,
+            map: Some(map),
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Flushes written data to disk, with `msync` for a mapped plot file.
+    pub fn sync(&self) -> Result<(), Error> {
+        match &self.map {
+            Some(map) => map.flush(),
+            None => self.file.sync_data(),
+        }
+    }
+
+    /// Whether every scoop region of a buffer starts and ends on a sector
+    /// boundary, so it can be written straight from the buffer.
+    pub fn is_aligned(&self, plot_nonces: u64, nonces_written: u64, nonces_to_write: u64) -> bool {
+        (self.base_offset + nonces_written * SCOOP_SIZE).is_multiple_of(self.sector_size)
+            && (plot_nonces * SCOOP_SIZE).is_multiple_of(self.sector_size)
+            && (nonces_to_write * SCOOP_SIZE).is_multiple_of(self.sector_size)
+    }
+
+    /// Writes the first `nonces_to_write` nonces of every scoop region of `bs`
+    /// with positioned writes. Scoop regions which are adjacent in the plot
+    /// file (only if the buffer holds the whole plot) are written vectored.
+    /// With direct i/o, unaligned scoop regions go through the bounce buffer.
+    pub fn write_buffer(
+        &mut self,
+        bs: &[u8],
+        plot_nonces: u64,
+        nonces_written: u64,
+        nonces_to_write: u64,
+        pb: &Option<ProgressBar>,
+    ) -> Result<(), Error> {
+        let buffer_nonces = bs.len() as u64 / NONCE_SIZE;
+        let region_size = nonces_to_write * SCOOP_SIZE;
+        let contiguous = nonces_to_write == plot_nonces;
+
+        if let Some(map) = self.map.as_mut() {
+            for scoop in 0..NUM_SCOOPS {
+                let map_addr = (scoop * plot_nonces * SCOOP_SIZE + nonces_written * SCOOP_SIZE) as usize;
+                let local_addr = (scoop * buffer_nonces * SCOOP_SIZE) as usize;
+                if let Some(limiter) = &self.limiter {
+                    limiter.acquire(region_size);
+                }
+                map[map_addr..map_addr + region_size as usize]
+                    .copy_from_slice(&bs[local_addr..local_addr + region_size as usize]);
+
+                if let Some(pb) = pb {
+                    pb.inc(region_size);
+                }
+            }
+            return Ok(());
+        }
+
+        if !self.is_aligned(plot_nonces, nonces_written, nonces_to_write) {
+            for scoop in 0..NUM_SCOOPS {
+                let seek_addr =
+                    self.base_offset + scoop * plot_nonces * SCOOP_SIZE + nonces_written * SCOOP_SIZE;
+                let local_addr = (scoop * buffer_nonces * SCOOP_SIZE) as usize;
+                let retry = self.retry;
+                retry.run("Write", || {
+                    self.write_unaligned(&bs[local_addr..local_addr + region_size as usize], seek_addr)
+                })?;
+
+                if let Some(pb) = pb {
+                    pb.inc(region_size);
+                }
+            }
+            return Ok(());
+        }
+
+        let mut scoop = 0;
+        while scoop < NUM_SCOOPS {
+            let seek_addr =
+                self.base_offset + scoop * plot_nonces * SCOOP_SIZE + nonces_written * SCOOP_SIZE;
+            let mut regions = Vec::new();
+            loop {
+                let local_addr = (scoop * buffer_nonces * SCOOP_SIZE) as usize;
+                regions.push(&bs[local_addr..local_addr + region_size as usize]);
+                scoop += 1;
+                if !contiguous || scoop == NUM_SCOOPS || regions.len() == MAX_IOVECS {
+                    break;
+                }
+            }
+            if let Some(limiter) = &self.limiter {
+                limiter.acquire(regions.len() as u64 * region_size);
+            }
+            self.retry.run("Write", || write_vectored_at(&self.file, &regions, seek_addr))?;
+
+            if let Some(pb) = pb {
+                pb.inc(regions.len() as u64 * region_size);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` of the file (not shifted by `base_offset`),
+    /// through the bounce buffer with direct i/o.
+    pub fn write_at(&mut self, data: &[u8], offset: u64) -> Result<(), Error> {
+        let retry = self.retry;
+        retry.run("Write", || self.write_unaligned(data, offset))
+    }
+
+    // writes `data` widened to whole sectors. The first and last sector are
+    // read before if `data` covers them partly, to keep the bytes around it.
+    fn write_unaligned(&mut self, data: &[u8], offset: u64) -> Result<(), Error> {
+        let start = offset / self.sector_size * self.sector_size;
+        let end = (offset + data.len() as u64).div_ceil(self.sector_size) * self.sector_size;
+        let sector_size = self.sector_size as usize;
+        let len = (end - start) as usize;
+        let head = (offset - start) as usize;
+
+        if self.bounce.len() < len + sector_size {
+            self.bounce.resize(len + sector_size, 0);
+        }
+        let pad = self.bounce.as_ptr().align_offset(sector_size);
+        let bounce = &mut self.bounce[pad..pad + len];
+
+        if head > 0 {
+            read_sector(&self.file, &mut bounce[..sector_size], start)?;
+        }
+        if head + data.len() < len && (head == 0 || len > sector_size) {
+            read_sector(&self.file, &mut bounce[len - sector_size..], end - sector_size as u64)?;
+        }
+        bounce[head..head + data.len()].copy_from_slice(data);
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(len as u64);
+        }
+        write_vectored_at(&self.file, &[bounce], start)
+    }
+}
+
+// sectors behind the end of the file read as zeros
+fn read_sector(file: &File, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+    match read_exact_at(file, buf, offset) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            buf.fill(0);
+            Ok(())
+        }
+        x => x,
+    }
+}
+
+/// Reads back a sample of the scoop regions of written buffers, bypassing
+/// the page cache, and compares them to the buffer.
+pub struct WriteVerifier {
+    file: File,
+    direct_io: bool,
+    sector_size: u64,
+    scoops: u64,
+    buf: Vec<u8>,
+}
+
+impl WriteVerifier {
+    pub fn open(path: &Path, sector_size: u64, scoops: u64) -> Result<WriteVerifier, Error> {
+        let (file, direct_io) = match open_using_direct_io(path) {
+            Ok(file) => (file, true),
+            Err(e) if e.raw_os_error() != Some(libc::EINVAL) => return Err(e),
+            // O_DIRECT isn't supported, the page cache is dropped before reading
+            Err(_) => (open_r(path)?, false),
+        };
+        Ok(WriteVerifier {
+            file,
+            direct_io,
+            sector_size: if direct_io { max(sector_size, 1) } else { 1 },
+            scoops: min(scoops, NUM_SCOOPS),
+            buf: Vec::new(),
+        })
+    }
+
+    /// False if written data is read back from the page cache, without
+    /// direct i/o where the cache can't be dropped.
+    pub fn reads_disk(&self) -> bool {
+        self.direct_io || cfg!(target_os = "linux")
+    }
+
+    /// Compares the sampled scoop regions of `bs` with the plot file.
+    /// `start_addr` is the file offset of the first nonce of the buffer in
+    /// scoop 0, `seed` shifts the sample so that successive buffers check
+    /// different scoops.
+    pub fn verify(
+        &mut self,
+        bs: &[u8],
+        plot_nonces: u64,
+        start_addr: u64,
+        nonces_to_write: u64,
+        seed: u64,
+    ) -> Result<(), Error> {
+        let buffer_nonces = bs.len() as u64 / NONCE_SIZE;
+        let region_size = nonces_to_write * SCOOP_SIZE;
+
+        // without direct i/o the buffer is flushed once, then the sampled
+        // ranges are dropped from the page cache
+        let drop_cache = !self.direct_io && self.reads_disk();
+        if drop_cache {
+            self.file.sync_data()?;
+        }
+
+        for i in 0..self.scoops {
+            let scoop = (i * NUM_SCOOPS / self.scoops + seed) % NUM_SCOOPS;
+            let seek_addr = start_addr + scoop * plot_nonces * SCOOP_SIZE;
+            let local_addr = (scoop * buffer_nonces * SCOOP_SIZE) as usize;
+
+            // direct i/o reads whole sectors
+            let start = seek_addr / self.sector_size * self.sector_size;
+            let end = (seek_addr + region_size).div_ceil(self.sector_size) * self.sector_size;
+            let len = (end - start) as usize;
+            let head = (seek_addr - start) as usize;
+
+            if drop_cache {
+                drop_page_cache(&self.file, start, end - start)?;
+            }
+            if self.buf.len() < len + self.sector_size as usize {
+                self.buf.resize(len + self.sector_size as usize, 0);
+            }
+            let pad = self.buf.as_ptr().align_offset(self.sector_size as usize);
+            let buf = &mut self.buf[pad..pad + len];
+            read_exact_at(&self.file, buf, start)?;
+
+            if buf[head..head + region_size as usize]
+                != bs[local_addr..local_addr + region_size as usize]
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("scoop {} differs from what was written", scoop),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a scratch plot in `dir` with each writer mode and returns the
+/// throughput in MiB/s, including the final flush to disk.
+pub fn bench_writers(dir: &Path, sector_size: u64) -> Vec<(&'static str, Result<f64, Error>)> {
+    let scratch = ScratchPath(dir.join(format!("anne-plotter-writer-benchmark-{}.tmp", process::id())));
+    ["buffered", "direct", "mmap"]
+        .iter()
+        .map(|&mode| (mode, bench_writer(&scratch, mode, sector_size)))
+        .collect()
+}
+
+fn bench_writer(path: &Path, mode: &str, sector_size: u64) -> Result<f64, Error> {
+    let plotsize = BENCH_NONCES * NONCE_SIZE;
+    let _ = std::fs::remove_file(path);
+    open(path)?.set_len(plotsize)?;
+
+    let mut file = match mode {
+        "direct" => PlotFile::open(path, true, 0, sector_size)?,
+        "mmap" => PlotFile::open_mmap(path, 0, plotsize, MmapAdvice::Random)?,
+        _ => PlotFile::open(path, false, 0, 0)?,
+    };
+    if mode == "direct" && !file.direct_io {
+        return Err(Error::new(ErrorKind::Unsupported, "direct i/o not supported"));
+    }
+
+    // direct i/o needs an aligned buffer
+    let buffer = PageAlignedByteBuffer::new((BENCH_BUFFER_NONCES * NONCE_SIZE) as usize);
+    let bs = buffer.get_buffer();
+    let mut bs = bs.lock().unwrap();
+    bs.fill(0xAF);
+
+    let start = Instant::now();
+    for nonces_written in (0..BENCH_NONCES).step_by(BENCH_BUFFER_NONCES as usize) {
+        file.write_buffer(&bs, BENCH_NONCES, nonces_written, BENCH_BUFFER_NONCES, &None)?;
+    }
+    file.sync()?;
+    Ok(plotsize as f64 / 1024.0 / 1024.0 / start.elapsed().as_secs_f64())
+}
+
 pub fn read_resume_info(file: &Path) -> Result<u64, Error> {
     let mut file = open_r(&file)?;
     file.seek(SeekFrom::End(-8))?;
@@ -135,15 +843,17 @@ pub fn read_resume_info(file: &Path) -> Result<u64, Error> {
 pub fn write_resume_info(file: &Path, nonces_written: u64) -> Result<(), Error> {
     let mut file = open(&file)?;
     file.seek(SeekFrom::End(-8))?;
-
-    let progress = as_u8_le(nonces_written as u32);
-    let double_monkey = [0xAF, 0xFE, 0xAF, 0xFE];
-
-    file.write_all(&progress[0..4])?;
-    file.write_all(&double_monkey[0..4])?;
+    file.write_all(&resume_marker(nonces_written))?;
     Ok(())    
 }
 
+// the progress followed by the double monkey
+fn resume_marker(nonces_written: u64) -> [u8; 8] {
+    let mut marker = [0xAF, 0xFE, 0xAF, 0xFE, 0xAF, 0xFE, 0xAF, 0xFE];
+    marker[0..4].copy_from_slice(&as_u8_le(nonces_written as u32));
+    marker
+}
+
 fn as_u32_le(array: [u8; 4]) -> u32 {
     u32::from(array[0])
         + (u32::from(array[1]) << 8)
@@ -157,4 +867,353 @@ fn as_u8_le(x: u32) -> [u8; 4] {
     let b3: u8 = ((x >> 16) & 0xff) as u8;
     let b4: u8 = ((x >> 24) & 0xff) as u8;
     [b1, b2, b3, b4]
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::ScratchPath;
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn unaligned_read_modify_write() {
+        let path = ScratchPath::temp("rmw");
+        let plot_nonces = 5u64;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(plot_nonces * NONCE_SIZE).unwrap();
+
+        // 4 KiB sectors hold 64 scoops, with 5 nonces most scoop regions
+        // start and end inside a sector
+        let mut plot_file = PlotFile {
+            file,
+            direct_io: true,
+            base_offset: 0,
+            sector_size: 4096,
+            bounce: Vec::new(),
+            limiter: None,
+            map: None,
+            retry: RetryPolicy::default(),
+        };
+        let buffer_nonces = 3u64;
+        assert!(!plot_file.is_aligned(plot_nonces, 0, buffer_nonces));
+
+        let mut nonces_written = 0;
+        while nonces_written < plot_nonces {
+            let nonces_to_write = min(buffer_nonces, plot_nonces - nonces_written);
+            let mut bs = vec![0u8; (buffer_nonces * NONCE_SIZE) as usize];
+            for scoop in 0..NUM_SCOOPS {
+                for n in 0..nonces_to_write {
+                    let local_addr = ((scoop * buffer_nonces + n) * SCOOP_SIZE) as usize;
+                    bs[local_addr] = (nonces_written + n + 1) as u8;
+                    bs[local_addr + 1] = (scoop % 251) as u8;
+                }
+            }
+            plot_file
+                .write_buffer(&bs, plot_nonces, nonces_written, nonces_to_write, &None)
+                .unwrap();
+            nonces_written += nonces_to_write;
+        }
+
+        let data = fs::read(&path).unwrap();
+        for scoop in 0..NUM_SCOOPS {
+            for n in 0..plot_nonces {
+                let addr = ((scoop * plot_nonces + n) * SCOOP_SIZE) as usize;
+                assert_eq!(data[addr], (n + 1) as u8);
+                assert_eq!(data[addr + 1], (scoop % 251) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn mmap_matches_blocking_writes() {
+        let plot_nonces = 5u64;
+        let base_offset = 8192u64;
+        let buffer_nonces = 2u64;
+        let mut files = Vec::new();
+        for mmap in [false, true] {
+            let path = ScratchPath::temp(&format!("mmap-{}", mmap));
+            open(&path).unwrap().set_len(base_offset + plot_nonces * NONCE_SIZE).unwrap();
+            let mut plot_file = if mmap {
+                PlotFile::open_mmap(&path, base_offset, plot_nonces * NONCE_SIZE, MmapAdvice::Random).unwrap()
+            } else {
+                PlotFile::open(&path, false, base_offset, 0).unwrap()
+            };
+
+            let mut nonces_written = 0;
+            while nonces_written < plot_nonces {
+                let nonces_to_write = min(buffer_nonces, plot_nonces - nonces_written);
+                let bs: Vec<u8> = (0..buffer_nonces * NONCE_SIZE)
+                    .map(|i| (i / SCOOP_SIZE + nonces_written) as u8)
+                    .collect();
+                plot_file
+                    .write_buffer(&bs, plot_nonces, nonces_written, nonces_to_write, &None)
+                    .unwrap();
+                nonces_written += nonces_to_write;
+            }
+            plot_file.sync().unwrap();
+            drop(plot_file);
+            files.push(fs::read(&path).unwrap());
+        }
+        assert!(files[0] == files[1]);
+        assert!(files[1][..base_offset as usize].iter().all(|&x| x == 0));
+
+        // a mapping can't reach behind the end of the file
+        let path = ScratchPath::temp("mmap-short");
+        open(&path).unwrap().set_len(NONCE_SIZE).unwrap();
+        assert!(PlotFile::open_mmap(&path, 0, 2 * NONCE_SIZE, MmapAdvice::Normal).is_err());
+    }
+
+    #[test]
+    fn retry_policy() {
+        let retry = RetryPolicy::new(2, Duration::from_millis(1));
+        let mut calls = 0;
+        let result = retry.run("Write", || {
+            calls += 1;
+            if calls < 3 { Err(Error::other("transient")) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        calls = 0;
+        let result: Result<(), Error> = retry.run("Write", || {
+            calls += 1;
+            Err(Error::other("persistent"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn verify_detects_corruption() {
+        let path = ScratchPath::temp("verify");
+        let plot_nonces = 4u64;
+        File::create(&path).unwrap().set_len(plot_nonces * NONCE_SIZE).unwrap();
+
+        let bs: Vec<u8> = (0..plot_nonces * NONCE_SIZE).map(|x| (x % 253) as u8).collect();
+        let mut plot_file = PlotFile::open(&path, false, 0, 0).unwrap();
+        plot_file.write_buffer(&bs, plot_nonces, 0, plot_nonces, &None).unwrap();
+
+        let mut verifier = WriteVerifier::open(&path, 4096, NUM_SCOOPS).unwrap();
+        verifier.verify(&bs, plot_nonces, 0, plot_nonces, 0).unwrap();
+
+        // flip a byte of the last nonce in scoop 1000
+        let addr = (1000 * plot_nonces + 3) * SCOOP_SIZE + 5;
+        write_vectored_at(&plot_file.file, &[&[!bs[addr as usize]]], addr).unwrap();
+        let err = verifier.verify(&bs, plot_nonces, 0, plot_nonces, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    // disk model: writes are cached until the next sync, a crash loses
+    // everything written after it
+    #[derive(Default)]
+    struct CrashDisk {
+        // operations in the order they were issued
+        ops: Vec<Op>,
+    }
+
+    enum Op {
+        Data(u64),
+        Marker(u64),
+        Sync,
+    }
+
+    impl Commit for CrashDisk {
+        fn sync_data(&mut self) -> Result<(), Error> {
+            self.ops.push(Op::Sync);
+            Ok(())
+        }
+
+        fn write_marker(&mut self, nonces_written: u64) -> Result<(), Error> {
+            self.ops.push(Op::Marker(nonces_written));
+            Ok(())
+        }
+
+        fn sync_marker(&mut self) -> Result<(), Error> {
+            self.ops.push(Op::Sync);
+            Ok(())
+        }
+    }
+
+    impl CrashDisk {
+        // (data, marker) that survive a crash after the first `n` operations
+        fn after_crash(&self, n: usize) -> (u64, u64) {
+            let synced = self.ops[..n].iter().rposition(|x| matches!(x, Op::Sync));
+            let persisted = match synced {
+                Some(x) => &self.ops[..x],
+                None => &[][..],
+            };
+            let mut state = (0, 0);
+            for op in persisted {
+                match op {
+                    Op::Data(x) => state.0 = *x,
+                    Op::Marker(x) => state.1 = *x,
+                    Op::Sync => (),
+                }
+            }
+            state
+        }
+    }
+
+    // plots 10 nonces in buffers of 3, returns the disk
+    fn plot_with_policy(interval: u64) -> CrashDisk {
+        let mut disk = CrashDisk::default();
+        let mut policy = SyncPolicy::new(interval);
+        let mut nonces_written = 0;
+        while nonces_written < 10 {
+            nonces_written = min(nonces_written + 3, 10);
+            disk.ops.push(Op::Data(nonces_written));
+            policy.buffer_written(&mut disk, nonces_written, 10).unwrap();
+        }
+        disk
+    }
+
+    #[test]
+    fn marker_never_ahead_of_data_after_crash() {
+        for interval in 1..=4 {
+            let disk = plot_with_policy(interval);
+            for n in 0..=disk.ops.len() {
+                let (data, marker) = disk.after_crash(n);
+                assert!(marker <= data, "interval {}, crash after op {}", interval, n);
+            }
+            assert_eq!(disk.after_crash(disk.ops.len()), (10, 10));
+        }
+    }
+
+    // disk model for the real commit path: the disk holds what the file held
+    // at the last sync. Of the writes since, the marker may have reached the
+    // disk before the data, so both are checked.
+    struct CrashFile {
+        path: PathBuf,
+        plot_nonces: u64,
+        synced: Vec<u8>,
+        marker: Option<Vec<u8>>,
+        // crash states with the marker ahead of the data
+        broken: usize,
+    }
+
+    impl CrashFile {
+        fn sync(&mut self) {
+            self.synced = fs::read(&self.path).unwrap();
+            self.marker = None;
+        }
+
+        fn check(&mut self) {
+            let mut states = vec![self.synced.clone()];
+            if let Some(marker) = &self.marker {
+                let mut disk = self.synced.clone();
+                let len = disk.len();
+                disk[len - 8..].copy_from_slice(marker);
+                states.push(disk);
+            }
+            for disk in states {
+                // the last buffer overwrites the marker, the plot is complete then
+                let marker = &disk[disk.len() - 8..];
+                let claimed = if marker[4..] == [0xAF, 0xFE, 0xAF, 0xFE] {
+                    u64::from(as_u32_le(marker[..4].try_into().unwrap()))
+                } else {
+                    self.plot_nonces
+                };
+                let data = (0..claimed).all(|n| {
+                    (0..NUM_SCOOPS).all(|scoop| {
+                        disk[((scoop * self.plot_nonces + n) * SCOOP_SIZE) as usize] == n as u8 + 1
+                    })
+                });
+                if !data {
+                    self.broken += 1;
+                }
+            }
+        }
+    }
+
+    struct CrashCommit<'a> {
+        commit: PlotCommit<'a>,
+        disk: &'a mut CrashFile,
+    }
+
+    impl Commit for CrashCommit<'_> {
+        fn sync_data(&mut self) -> Result<(), Error> {
+            self.commit.sync_data()?;
+            self.disk.sync();
+            self.disk.check();
+            Ok(())
+        }
+
+        fn write_marker(&mut self, nonces_written: u64) -> Result<(), Error> {
+            self.commit.write_marker(nonces_written)?;
+            let file = fs::read(&self.disk.path).unwrap();
+            self.disk.marker = Some(file[file.len() - 8..].to_vec());
+            self.disk.check();
+            Ok(())
+        }
+
+        fn sync_marker(&mut self) -> Result<(), Error> {
+            self.commit.sync_marker()?;
+            self.disk.sync();
+            self.disk.check();
+            Ok(())
+        }
+    }
+
+    // plots 6 nonces in buffers of 2 through PlotCommit, returns the crash
+    // states with the marker ahead of the data
+    fn plot_file_with_policy(interval: u64, mmap: bool) -> usize {
+        let plot_nonces = 6u64;
+        let buffer_nonces = 2u64;
+        let path = ScratchPath::temp(&format!("crash-{}-{}", interval, mmap));
+        open(&path).unwrap().set_len(plot_nonces * NONCE_SIZE).unwrap();
+        write_resume_info(&path, 0).unwrap();
+        let mut plot_file = if mmap {
+            PlotFile::open_mmap(&path, 0, plot_nonces * NONCE_SIZE, MmapAdvice::Random).unwrap()
+        } else {
+            PlotFile::open(&path, false, 0, 0).unwrap()
+        };
+        let mut target = PlotTarget::File(path.to_path_buf());
+        let mut disk = CrashFile {
+            path: path.to_path_buf(),
+            plot_nonces,
+            synced: Vec::new(),
+            marker: None,
+            broken: 0,
+        };
+        disk.sync();
+
+        let mut policy = SyncPolicy::new(interval);
+        let mut nonces_written = 0;
+        while nonces_written < plot_nonces {
+            let bs: Vec<u8> = (0..buffer_nonces * NONCE_SIZE)
+                .map(|i| (i / SCOOP_SIZE % buffer_nonces + nonces_written + 1) as u8)
+                .collect();
+            plot_file
+                .write_buffer(&bs, plot_nonces, nonces_written, buffer_nonces, &None)
+                .unwrap();
+            disk.check();
+            nonces_written += buffer_nonces;
+
+            let mut commit = CrashCommit {
+                commit: PlotCommit {
+                    data: &mut plot_file,
+                    target: &mut target,
+                    plot_nonces,
+                },
+                disk: &mut disk,
+            };
+            policy.buffer_written(&mut commit, nonces_written, plot_nonces).unwrap();
+        }
+        disk.broken
+    }
+
+    #[test]
+    fn plot_file_marker_never_ahead_of_data_after_crash() {
+        for mmap in [false, true] {
+            for interval in 1..=3 {
+                assert_eq!(plot_file_with_policy(interval, mmap), 0, "interval {}, mmap {}", interval, mmap);
+            }
+            // without syncing, the marker may reach the disk before the data
+            assert!(plot_file_with_policy(0, mmap) > 0, "mmap {}", mmap);
+        }
+    }
+}